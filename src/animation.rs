@@ -0,0 +1,353 @@
+use std::io::Cursor;
+
+use anyhow::{Context, Result, anyhow};
+use image::{
+    AnimationDecoder, Delay, DynamicImage, Frame, ImageDecoder, ImageFormat, Limits, RgbaImage,
+    codecs::{
+        gif::{GifDecoder, GifEncoder, Repeat},
+        png::PngDecoder,
+        webp::{WebPDecoder, WebPEncoder},
+    },
+    imageops,
+    metadata::Orientation,
+};
+
+use crate::{
+    environment::{
+        IMAGE_MAX_ANIMATION_PIXELS, IMAGE_MAX_DECODE_BYTES, IMAGE_MAX_FRAMES,
+        IMAGE_MAX_SOURCE_PIXELS,
+    },
+    routes::preview_image::ImageLimitError,
+};
+
+// gifsicle's speed 10 trades a little size for much faster quantization
+const GIF_SPEED: i32 = 10;
+// WebP frame durations are stored in 24 bits
+const MAX_WEBP_DURATION: u32 = 0xff_ffff;
+
+pub struct AnimationFrame {
+    pub image: RgbaImage,
+    pub delay_ms: u32,
+}
+
+/// The frames of an animated GIF, APNG or WebP, each composited onto the
+/// full canvas.
+pub struct Animation {
+    pub frames: Vec<AnimationFrame>,
+    // How often the animation plays, 0 meaning forever
+    pub loop_count: u16,
+}
+
+/// Decodes every frame of an animated image. Returns None for formats that
+/// can't be animated and for images with a single frame.
+pub fn decode(data: &[u8]) -> Result<Option<Animation>> {
+    let Ok(format) = image::guess_format(data) else {
+        return Ok(None);
+    };
+    // Single-frame images are left to the static decoder instead of being
+    // decoded twice
+    if frame_count(data) < 2 {
+        return Ok(None);
+    }
+    let mut limits = Limits::default();
+    limits.max_alloc = Some(*IMAGE_MAX_DECODE_BYTES);
+    let (mut frames, loop_count, orientation) = match format {
+        ImageFormat::Gif => {
+            let mut decoder = GifDecoder::new(Cursor::new(data))?;
+            decoder.set_limits(limits)?;
+            let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+            let frames = collect_frames(decoder.dimensions(), decoder.into_frames())?;
+            (frames, gif_loop_count(data), orientation)
+        }
+        ImageFormat::Png => {
+            let mut decoder = PngDecoder::new(Cursor::new(data))?;
+            if !decoder.is_apng()? {
+                return Ok(None);
+            }
+            decoder.set_limits(limits)?;
+            let dimensions = decoder.dimensions();
+            let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+            let frames = collect_frames(dimensions, decoder.apng()?.into_frames())?;
+            (frames, apng_loop_count(data), orientation)
+        }
+        ImageFormat::WebP => {
+            let mut decoder = WebPDecoder::new(Cursor::new(data))?;
+            if !decoder.has_animation() {
+                return Ok(None);
+            }
+            decoder.set_limits(limits)?;
+            let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+            let frames = collect_frames(decoder.dimensions(), decoder.into_frames())?;
+            (frames, webp_loop_count(data), orientation)
+        }
+        _ => return Ok(None),
+    };
+    if frames.len() < 2 {
+        return Ok(None);
+    }
+    if orientation != Orientation::NoTransforms {
+        for frame in &mut frames {
+            let mut image = DynamicImage::ImageRgba8(std::mem::take(&mut frame.image));
+            image.apply_orientation(orientation);
+            frame.image = image.into_rgba8();
+        }
+    }
+    Ok(Some(Animation { frames, loop_count }))
+}
+
+/// Counts the frames of a GIF, APNG or WebP from its structure, without
+/// decoding them. Other images have a single frame.
+pub fn frame_count(data: &[u8]) -> u32 {
+    let count = match image::guess_format(data) {
+        Ok(ImageFormat::Gif) => gif_frame_count(data),
+        // num_frames from the acTL chunk
+        Ok(ImageFormat::Png) => apng_control(data)
+            .map(|control| u32::from_be_bytes([control[0], control[1], control[2], control[3]]))
+            .unwrap_or(1),
+        Ok(ImageFormat::WebP) => riff_chunks(data)
+            .filter(|(fourcc, _)| fourcc == b"ANMF")
+            .count() as u32,
+        _ => 1,
+    };
+    count.max(1)
+}
+
+/// Counts the image descriptors of a GIF, skipping over the image data and
+/// extensions.
+fn gif_frame_count(data: &[u8]) -> u32 {
+    let color_table_size = |flags: u8| {
+        if flags & 0x80 != 0 {
+            3 << ((flags & 7) + 1)
+        } else {
+            0
+        }
+    };
+    // Header, then the logical screen descriptor and global color table
+    let Some(&flags) = data.get(10) else {
+        return 0;
+    };
+    let mut offset = 13 + color_table_size(flags);
+    let mut count = 0;
+    loop {
+        match data.get(offset) {
+            // Image descriptor, local color table and LZW minimum code size
+            Some(0x2c) => {
+                let Some(&flags) = data.get(offset + 9) else {
+                    return count;
+                };
+                count += 1;
+                offset += 11 + color_table_size(flags);
+            }
+            // Extension introducer and label
+            Some(0x21) => offset += 2,
+            // Trailer, or data that isn't a block
+            _ => return count,
+        }
+        // Data sub-blocks, ended by an empty one
+        loop {
+            match data.get(offset) {
+                Some(0) => {
+                    offset += 1;
+                    break;
+                }
+                Some(&length) => offset += 1 + length as usize,
+                None => return count,
+            }
+        }
+    }
+}
+
+/// Rejects animations exceeding `IMAGE_MAX_FRAMES` or, counting every frame,
+/// `IMAGE_MAX_ANIMATION_PIXELS`.
+pub fn check_budget(frame_count: usize, width: u32, height: u32) -> Result<(), ImageLimitError> {
+    if frame_count > *IMAGE_MAX_FRAMES {
+        return Err(ImageLimitError::TooManyFrames(*IMAGE_MAX_FRAMES));
+    }
+    if frame_count as u64 * width as u64 * height as u64 > *IMAGE_MAX_ANIMATION_PIXELS {
+        return Err(ImageLimitError::AnimationTooLarge(
+            *IMAGE_MAX_ANIMATION_PIXELS,
+        ));
+    }
+    Ok(())
+}
+
+/// Collects frames while enforcing the budget, so oversized animations are
+/// rejected before all of their frames are held in memory. The canvas is held
+/// to the same `IMAGE_MAX_SOURCE_PIXELS` limit as static images.
+fn collect_frames(
+    (width, height): (u32, u32),
+    frames: image::Frames<'_>,
+) -> Result<Vec<AnimationFrame>> {
+    let pixels = width as u64 * height as u64;
+    if pixels > *IMAGE_MAX_SOURCE_PIXELS {
+        return Err(ImageLimitError::TooManyPixels(pixels, *IMAGE_MAX_SOURCE_PIXELS).into());
+    }
+    let mut collected = Vec::new();
+    for frame in frames {
+        check_budget(collected.len() + 1, width, height)?;
+        let frame = frame.context("Failed to decode animation frame")?;
+        let delay_ms = delay_to_ms(frame.delay());
+        let (left, top) = (frame.left(), frame.top());
+        let mut image = frame.into_buffer();
+        if (left, top) != (0, 0) || image.dimensions() != (width, height) {
+            let mut canvas = RgbaImage::new(width, height);
+            imageops::overlay(&mut canvas, &image, left as i64, top as i64);
+            image = canvas;
+        }
+        collected.push(AnimationFrame { image, delay_ms });
+    }
+    Ok(collected)
+}
+
+fn delay_to_ms(delay: Delay) -> u32 {
+    let (numerator, denominator) = delay.numer_denom_ms();
+    numerator.checked_div(denominator).unwrap_or(0)
+}
+
+/// Reads the loop count from the NETSCAPE2.0 application extension, which
+/// counts repeats after the first play. GIFs without one play once.
+fn gif_loop_count(data: &[u8]) -> u16 {
+    const EXTENSION: &[u8] = b"NETSCAPE2.0";
+    data.windows(EXTENSION.len())
+        .position(|window| window == EXTENSION)
+        .and_then(|position| data.get(position + EXTENSION.len()..position + EXTENSION.len() + 4))
+        .filter(|block| block[0] == 3 && block[1] == 1)
+        .map(|block| match u16::from_le_bytes([block[2], block[3]]) {
+            0 => 0,
+            repeats => repeats.saturating_add(1),
+        })
+        .unwrap_or(1)
+}
+
+/// Returns the payload of the acTL chunk, present in animated PNGs.
+fn apng_control(data: &[u8]) -> Option<&[u8]> {
+    let mut offset = 8;
+    while let Some(header) = data.get(offset..offset + 8) {
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        if &header[4..8] == b"acTL" {
+            return data.get(offset + 8..offset + 16);
+        }
+        // Chunk length, type, data and CRC
+        offset += 12 + length;
+    }
+    None
+}
+
+/// Reads `num_plays` from the acTL chunk.
+fn apng_loop_count(data: &[u8]) -> u16 {
+    apng_control(data)
+        .map(|control| u32::from_be_bytes([control[4], control[5], control[6], control[7]]))
+        .map(|plays| plays.min(u16::MAX as u32) as u16)
+        .unwrap_or(0)
+}
+
+/// Reads the loop count from the ANIM chunk.
+fn webp_loop_count(data: &[u8]) -> u16 {
+    riff_chunks(data)
+        .find(|(fourcc, _)| fourcc == b"ANIM")
+        .and_then(|(_, payload)| payload.get(4..6))
+        .map(|count| u16::from_le_bytes([count[0], count[1]]))
+        .unwrap_or(0)
+}
+
+/// Iterates over the chunks of a WebP file after the RIFF header.
+pub fn riff_chunks(data: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    let mut offset = 12;
+    std::iter::from_fn(move || {
+        let header = data.get(offset..offset + 8)?;
+        let fourcc = [header[0], header[1], header[2], header[3]];
+        let size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let payload = data.get(offset + 8..offset + 8 + size)?;
+        // Chunks are padded to an even size
+        offset += 8 + size + (size & 1);
+        Some((fourcc, payload))
+    })
+}
+
+pub fn write_chunk(output: &mut Vec<u8>, fourcc: &[u8; 4], payload: &[u8]) {
+    output.extend_from_slice(fourcc);
+    output.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    output.extend_from_slice(payload);
+    if payload.len() % 2 == 1 {
+        output.push(0);
+    }
+}
+
+fn push_u24(output: &mut Vec<u8>, value: u32) {
+    output.extend_from_slice(&value.to_le_bytes()[..3]);
+}
+
+pub fn encode_gif(animation: &Animation) -> Result<Vec<u8>> {
+    let mut output = Vec::new();
+    {
+        let mut encoder = GifEncoder::new_with_speed(&mut output, GIF_SPEED);
+        encoder.set_repeat(match animation.loop_count {
+            0 => Repeat::Infinite,
+            plays => Repeat::Finite(plays - 1),
+        })?;
+        let frames = animation.frames.iter().map(|frame| {
+            Frame::from_parts(
+                frame.image.clone(),
+                0,
+                0,
+                Delay::from_numer_denom_ms(frame.delay_ms, 1),
+            )
+        });
+        encoder
+            .encode_frames(frames)
+            .context("Failed to encode animated GIF")?;
+    }
+    Ok(output)
+}
+
+/// Encodes an animated WebP. The WebP encoder only writes still images, so
+/// every frame is encoded losslessly on its own and the bitstreams are muxed
+/// into ANMF chunks.
+pub fn encode_webp(animation: &Animation) -> Result<Vec<u8>> {
+    let first = animation
+        .frames
+        .first()
+        .ok_or_else(|| anyhow!("Animation has no frames"))?;
+    let (width, height) = first.image.dimensions();
+
+    let mut chunks = Vec::new();
+    let mut header = Vec::with_capacity(10);
+    // Animation and alpha flags
+    header.extend_from_slice(&[0x12, 0, 0, 0]);
+    push_u24(&mut header, width - 1);
+    push_u24(&mut header, height - 1);
+    write_chunk(&mut chunks, b"VP8X", &header);
+    let mut anim = vec![0, 0, 0, 0];
+    anim.extend_from_slice(&animation.loop_count.to_le_bytes());
+    write_chunk(&mut chunks, b"ANIM", &anim);
+
+    for frame in &animation.frames {
+        let mut still = Cursor::new(Vec::new());
+        DynamicImage::ImageRgba8(frame.image.clone())
+            .write_with_encoder(WebPEncoder::new_lossless(&mut still))
+            .context("Failed to encode WebP frame")?;
+        let still = still.into_inner();
+
+        let mut anmf = Vec::new();
+        push_u24(&mut anmf, 0);
+        push_u24(&mut anmf, 0);
+        push_u24(&mut anmf, width - 1);
+        push_u24(&mut anmf, height - 1);
+        push_u24(&mut anmf, frame.delay_ms.min(MAX_WEBP_DURATION));
+        // Frames cover the whole canvas: no blending, no disposal
+        anmf.push(0b10);
+        for (fourcc, payload) in riff_chunks(&still) {
+            if matches!(&fourcc, b"VP8L" | b"VP8 " | b"ALPH") {
+                write_chunk(&mut anmf, &fourcc, payload);
+            }
+        }
+        write_chunk(&mut chunks, b"ANMF", &anmf);
+    }
+
+    let mut output = Vec::with_capacity(chunks.len() + 12);
+    output.extend_from_slice(b"RIFF");
+    output.extend_from_slice(&(chunks.len() as u32 + 4).to_le_bytes());
+    output.extend_from_slice(b"WEBP");
+    output.extend_from_slice(&chunks);
+    Ok(output)
+}
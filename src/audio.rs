@@ -0,0 +1,187 @@
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, anyhow};
+use bytes::Bytes;
+use log::debug;
+use symphonia::core::{
+    audio::SampleBuffer,
+    codecs::{CODEC_TYPE_NULL, DecoderOptions},
+    errors::Error as SymphoniaError,
+    formats::FormatOptions,
+    io::MediaSourceStream,
+    meta::MetadataOptions,
+    probe::Hint,
+};
+
+use crate::{
+    database::{AudioMetadata, WaveformUnavailable},
+    environment::MEDIA_PROBE_TIMEOUT_SECONDS,
+    routes::preview_image::run_blocking,
+};
+
+const WAVEFORM_PEAKS: usize = 100;
+// Peaks are first taken over blocks this long, then merged into the waveform
+const BLOCK_MS: u64 = 10;
+// Longer audio is not decoded in full, so it gets no waveform
+const MAX_DECODE_MS: u64 = 20 * 60 * 1000;
+
+/// Reads the duration, sample rate and channel count of an audio file and
+/// decodes it into a waveform of `WAVEFORM_PEAKS` peaks, on the image worker
+/// pool. Decoding stops after `MEDIA_PROBE_TIMEOUT_SECONDS` so long files
+/// don't hold a worker.
+///
+/// Only the codecs symphonia 0.5 decodes get a waveform. Opus, as recorded
+/// by browsers into WebM or Ogg, is not among them; such files are
+/// reported with `WaveformUnavailable::UnsupportedCodec`.
+pub async fn analyze(data: Bytes, content_type: &str) -> Result<AudioMetadata> {
+    let content_type = content_type.to_string();
+    run_blocking(move || analyze_audio(data, &content_type)).await
+}
+
+fn analyze_audio(data: Bytes, content_type: &str) -> Result<AudioMetadata> {
+    let source = MediaSourceStream::new(Box::new(Cursor::new(data)), Default::default());
+    let mut hint = Hint::new();
+    hint.mime_type(content_type);
+    let probed = symphonia::default::get_probe()
+        .format(
+            &hint,
+            source,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .context("Failed to read audio container")?;
+    let mut format = probed.format;
+    let track = format
+        .tracks()
+        .iter()
+        .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or_else(|| anyhow!("No audio track found"))?;
+    let track_id = track.id;
+    let params = track.codec_params.clone();
+
+    let sample_rate = params.sample_rate;
+    let channels = params.channels.map(|channels| channels.count() as u16);
+    let mut duration_ms = params
+        .n_frames
+        .zip(params.time_base)
+        .map(|(frames, time_base)| {
+            let time = time_base.calc_time(frames);
+            time.seconds * 1000 + (time.frac * 1000.0).round() as u64
+        });
+
+    let mut decoder =
+        match symphonia::default::get_codecs().make(&params, &DecoderOptions::default()) {
+            Ok(decoder) => decoder,
+            Err(SymphoniaError::Unsupported(codec)) => {
+                debug!("Not decoding unsupported audio codec {}", codec);
+                return Ok(AudioMetadata {
+                    duration_ms,
+                    sample_rate,
+                    channels,
+                    waveform: None,
+                    waveform_unavailable: Some(WaveformUnavailable::UnsupportedCodec),
+                });
+            }
+            Err(e) => return Err(e).context("Failed to create audio decoder"),
+        };
+
+    let mut blocks = Vec::new();
+    let mut block_peak = 0f32;
+    let mut block_frames = 0u64;
+    let mut decoded_frames = 0u64;
+    let mut decoded_rate = sample_rate.unwrap_or(0);
+    let mut samples: Option<SampleBuffer<f32>> = None;
+    let mut complete = true;
+    let deadline = Instant::now() + Duration::from_secs(*MEDIA_PROBE_TIMEOUT_SECONDS);
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                break;
+            }
+            // Chained streams with different parameters are cut short
+            Err(SymphoniaError::ResetRequired) => break,
+            Err(e) => return Err(e).context("Failed to read audio packet"),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // Corrupt packets are skipped, as players do
+            Err(SymphoniaError::DecodeError(_)) => continue,
+            Err(e) => return Err(e).context("Failed to decode audio"),
+        };
+        let spec = *decoded.spec();
+        decoded_rate = spec.rate;
+        let channel_count = spec.channels.count().max(1);
+        let buffer = match &mut samples {
+            Some(buffer) if buffer.capacity() >= decoded.capacity() * channel_count => buffer,
+            _ => samples.insert(SampleBuffer::new(decoded.capacity() as u64, spec)),
+        };
+        buffer.copy_interleaved_ref(decoded);
+
+        let block_size = (spec.rate as u64 * BLOCK_MS / 1000).max(1);
+        for frame in buffer.samples().chunks(channel_count) {
+            let peak = frame
+                .iter()
+                .fold(0f32, |peak, sample| peak.max(sample.abs()));
+            block_peak = block_peak.max(peak);
+            block_frames += 1;
+            if block_frames == block_size {
+                blocks.push(block_peak);
+                block_peak = 0.0;
+                block_frames = 0;
+            }
+        }
+        decoded_frames += (buffer.samples().len() / channel_count) as u64;
+        if (decoded_rate > 0 && decoded_frames * 1000 / decoded_rate as u64 > MAX_DECODE_MS)
+            || Instant::now() >= deadline
+        {
+            complete = false;
+            break;
+        }
+    }
+    if block_frames > 0 {
+        blocks.push(block_peak);
+    }
+
+    // Containers without a frame count are timed by what was decoded
+    if duration_ms.is_none() && complete && decoded_rate > 0 {
+        duration_ms = Some(decoded_frames * 1000 / decoded_rate as u64);
+    }
+    Ok(AudioMetadata {
+        duration_ms,
+        sample_rate: sample_rate.or((decoded_rate > 0).then_some(decoded_rate)),
+        channels,
+        waveform: complete.then(|| waveform(&blocks)),
+        waveform_unavailable: (!complete).then_some(WaveformUnavailable::TooLong),
+    })
+}
+
+/// Merges block peaks into `WAVEFORM_PEAKS` peaks scaled to 0-255. Audio
+/// shorter than that many blocks yields fewer peaks.
+fn waveform(blocks: &[f32]) -> Vec<u8> {
+    if blocks.is_empty() {
+        return Vec::new();
+    }
+    let count = WAVEFORM_PEAKS.min(blocks.len());
+    let peaks: Vec<f32> = (0..count)
+        .map(|index| {
+            let start = index * blocks.len() / count;
+            let end = (index + 1) * blocks.len() / count;
+            blocks[start..end]
+                .iter()
+                .fold(0f32, |peak, block| peak.max(*block))
+        })
+        .collect();
+    let loudest = peaks.iter().fold(0f32, |loudest, peak| loudest.max(*peak));
+    if loudest <= 0.0 {
+        return vec![0; peaks.len()];
+    }
+    peaks
+        .iter()
+        .map(|peak| (peak / loudest * 255.0).round() as u8)
+        .collect()
+}
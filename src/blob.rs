@@ -0,0 +1,85 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, anyhow};
+use log::{info, warn};
+use tokio::time::sleep;
+
+use crate::{
+    database::{BlobRepository, BlobState},
+    environment::S3_BUCKET,
+    storage,
+};
+
+// How long to wait for a blob with the same hash to finish being deleted
+const DELETING_WAIT: Duration = Duration::from_secs(30);
+const DELETING_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+pub fn blob_key(hash: &str) -> String {
+    format!("blobs/{}", hash)
+}
+
+/// Adds a reference to the blob with the given hash, uploading the data to S3
+/// unless the blob is already stored. A blob that is still pending is
+/// uploaded again rather than trusting another upload to succeed; the
+/// content is identical, so either upload stores the same object.
+pub async fn store(hash: &str, data: &[u8], content_type: &str) -> Result<()> {
+    let deadline = Instant::now() + DELETING_WAIT;
+    loop {
+        match BlobRepository::acquire(hash, data.len() as u64)
+            .await
+            .context("Failed to reference blob")?
+        {
+            Some(BlobState::Stored) => {
+                info!("Blob {} already stored, skipping upload", hash);
+                return Ok(());
+            }
+            Some(BlobState::Deleting) => {
+                if Instant::now() >= deadline {
+                    return Err(anyhow!("Blob {} is still being deleted", hash));
+                }
+                sleep(DELETING_POLL_INTERVAL).await;
+            }
+            None | Some(BlobState::Pending) => break,
+        }
+    }
+
+    let stored = match S3_BUCKET
+        .put_object_with_content_type(blob_key(hash), data, content_type)
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(storage::check_response)
+    {
+        Ok(_) => BlobRepository::mark_stored(hash)
+            .await
+            .context("Failed to mark blob as stored"),
+        Err(e) => Err(e).context("Failed to upload blob to S3"),
+    };
+    if let Err(e) = stored {
+        if let Err(e) = release(hash).await {
+            warn!("Failed to release blob {} after failed upload: {}", hash, e);
+        }
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Removes a reference to the blob, deleting the object from S3 once the last
+/// file pointing to it is gone.
+pub async fn release(hash: &str) -> Result<()> {
+    let deleting = BlobRepository::release(hash)
+        .await
+        .context("Failed to release blob")?;
+    if deleting {
+        S3_BUCKET
+            .delete_object(blob_key(hash))
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(storage::check_response)
+            .context("Failed to delete blob from S3")?;
+        BlobRepository::remove(&[hash.to_string()])
+            .await
+            .context("Failed to remove deleted blob")?;
+        info!("Deleted unreferenced blob {} from S3", hash);
+    }
+    Ok(())
+}
@@ -1,24 +1,151 @@
-use anyhow::{Context, Result};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, anyhow};
 use bytes::Bytes;
 use clamav_client;
-use log::debug;
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+
+use crate::{
+    database::{ScanVerdictDocument, ScanVerdictRepository},
+    environment::{CLAMAV_HOST, CLAMAV_PORT, CLAMAV_VERSION_CACHE_SECONDS},
+};
+
+lazy_static! {
+    static ref DEFINITIONS_VERSION: Mutex<Option<(String, Instant)>> = Mutex::new(None);
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScanVerdict {
+    Clean,
+    // Names of the signatures that matched, as reported by clamd
+    Infected(Vec<String>),
+}
+
+impl ScanVerdict {
+    pub fn is_clean(&self) -> bool {
+        matches!(self, ScanVerdict::Clean)
+    }
+
+    pub fn signatures(&self) -> &[String] {
+        match self {
+            ScanVerdict::Clean => &[],
+            ScanVerdict::Infected(signatures) => signatures,
+        }
+    }
+}
+
+impl From<ScanVerdictDocument> for ScanVerdict {
+    fn from(document: ScanVerdictDocument) -> Self {
+        if document.clean {
+            ScanVerdict::Clean
+        } else {
+            ScanVerdict::Infected(document.signatures)
+        }
+    }
+}
+
+/// Extracts signature names from a clamd response such as
+/// `stream: Eicar-Test-Signature FOUND`.
+fn parse_signatures(response: &str) -> Vec<String> {
+    response
+        .split(['\0', '\n'])
+        .filter_map(|line| line.trim().strip_suffix(" FOUND"))
+        .map(|line| {
+            line.split_once(": ")
+                .map(|(_, name)| name)
+                .unwrap_or(line)
+                .to_string()
+        })
+        .collect()
+}
 
-use crate::environment::{CLAMAV_HOST, CLAMAV_PORT};
+fn clamd() -> clamav_client::tokio::Tcp<String> {
+    clamav_client::tokio::Tcp {
+        host_address: format!("{}:{}", &*CLAMAV_HOST, &*CLAMAV_PORT),
+    }
+}
 
-pub async fn scan_bytes(data: &Bytes) -> Result<bool> {
-    let address = format!("{}:{}", &*CLAMAV_HOST, &*CLAMAV_PORT);
+pub async fn scan_bytes(data: &Bytes) -> Result<ScanVerdict> {
     let data_owned = data.clone();
 
     debug!("Sending {} bytes to ClamAV for scanning", data_owned.len());
-    let clamd = clamav_client::tokio::Tcp {
-        host_address: address,
-    };
-    let response = clamav_client::tokio::scan_buffer(&data_owned, clamd, None)
+    let response = clamav_client::tokio::scan_buffer(&data_owned, clamd(), None)
         .await
         .context("Failed to scan file with ClamAV")?;
     let response_str = String::from_utf8_lossy(&response);
     debug!("ClamAV response: {}", response_str);
 
     let is_clean = clamav_client::clean(&response).context("Failed to parse ClamAV response")?;
-    Ok(is_clean)
+    if is_clean {
+        return Ok(ScanVerdict::Clean);
+    }
+    let signatures = parse_signatures(&response_str);
+    if signatures.is_empty() {
+        return Err(anyhow!("Unexpected ClamAV response: {}", response_str));
+    }
+    Ok(ScanVerdict::Infected(signatures))
+}
+
+/// Returns the signature database version reported by clamd, e.g. `27400`
+/// for `ClamAV 1.4.1/27400/Wed Oct 15 08:00:00 2026`. The value is cached
+/// for `CLAMAV_VERSION_CACHE_SECONDS` to avoid a round trip per upload.
+pub async fn definitions_version() -> Result<String> {
+    if let Some((version, fetched_at)) = DEFINITIONS_VERSION.lock().unwrap().as_ref()
+        && fetched_at.elapsed() < Duration::from_secs(*CLAMAV_VERSION_CACHE_SECONDS)
+    {
+        return Ok(version.clone());
+    }
+
+    let response = clamav_client::tokio::get_version(clamd())
+        .await
+        .context("Failed to get ClamAV version")?;
+    let response_str = String::from_utf8_lossy(&response);
+    let version = response_str
+        .trim_end_matches(['\0', '\n'])
+        .split('/')
+        .nth(1)
+        .ok_or_else(|| anyhow!("Unexpected ClamAV version response: {}", response_str))?
+        .to_string();
+    debug!("ClamAV definitions version: {}", version);
+
+    *DEFINITIONS_VERSION.lock().unwrap() = Some((version.clone(), Instant::now()));
+    Ok(version)
+}
+
+/// Scans the data unless a verdict for its SHA-256 hash is already known.
+/// Infected hashes are rejected without contacting clamd; clean verdicts are
+/// only reused if they were produced by the current definitions version.
+pub async fn scan_bytes_cached(data: &Bytes, hash: &str) -> Result<ScanVerdict> {
+    match ScanVerdictRepository::find_infected(hash).await {
+        Ok(Some(verdict)) => {
+            info!("Hash {} is known to be infected", hash);
+            return Ok(verdict.into());
+        }
+        Ok(None) => {}
+        Err(e) => warn!("Failed to look up scan verdict for {}: {}", hash, e),
+    }
+
+    let version = definitions_version().await?;
+    match ScanVerdictRepository::find_verdict(hash, &version).await {
+        Ok(Some(verdict)) => {
+            info!("Using cached scan verdict for {}", hash);
+            return Ok(verdict.into());
+        }
+        Ok(None) => {}
+        Err(e) => warn!("Failed to look up scan verdict for {}: {}", hash, e),
+    }
+
+    let verdict = scan_bytes(data).await?;
+    let document = ScanVerdictDocument::new(
+        hash.to_string(),
+        version,
+        verdict.is_clean(),
+        verdict.signatures().to_vec(),
+    );
+    if let Err(e) = ScanVerdictRepository::save_verdict(document).await {
+        warn!("Failed to cache scan verdict for {}: {}", hash, e);
+    }
+    Ok(verdict)
 }
@@ -1,16 +1,24 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
 use anyhow::Result;
 use futures_util::StreamExt;
 use log::error;
 use mongodb::{
-    Client, Collection,
-    bson::{DateTime, doc},
+    Client, Collection, IndexModel,
+    bson::{DateTime, Document, doc},
+    error::{ErrorKind, WriteFailure},
+    options::{IndexOptions, ReturnDocument},
 };
 use rand::{Rng, distr::Alphanumeric};
 use serde::{Deserialize, Serialize};
+use ulid::Ulid;
 
 use crate::{
+    blob, derivative,
     environment::{AS_MONGODB_DATABASE, FILE_TIMEOUT_HOURS, MONGODB_DATABASE},
     get_time_millis,
+    preview_cache::CachedPreview,
 };
 
 use crate::environment::MONGODB_URI;
@@ -24,6 +32,33 @@ pub async fn connect() {
         .await
         .expect("Failed to connect to MongoDB");
     DATABASE.set(client).expect("Failed to set MongoDB client");
+    ScanVerdictRepository::create_indexes()
+        .await
+        .expect("Failed to create scan verdict indexes");
+    BlobRepository::create_indexes()
+        .await
+        .expect("Failed to create blob indexes");
+    LockRepository::create_indexes()
+        .await
+        .expect("Failed to create lock indexes");
+    PreviewCacheRepository::create_indexes()
+        .await
+        .expect("Failed to create preview cache indexes");
+    DerivativeRepository::create_indexes()
+        .await
+        .expect("Failed to create derivative indexes");
+    VariantRepository::create_indexes()
+        .await
+        .expect("Failed to create variant indexes");
+}
+
+/// An object a document points to, as listed for reconciliation.
+#[derive(Debug, Clone)]
+pub struct ObjectReference {
+    // Id or hash of the document, for reporting
+    pub id: String,
+    pub key: String,
+    pub created_at: DateTime,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -36,6 +71,11 @@ pub struct FileDocument {
     pub user_id: String,
     pub signing_key: String,
 
+    // SHA-256 of the content, referencing a BlobDocument
+    // Files uploaded before deduplication store their object under `id`
+    #[serde(default)]
+    pub blob: Option<String>,
+
     // This attribute should be set by other applications
     // If false for too long, the file will be deleted
     pub linked: bool,
@@ -45,6 +85,66 @@ pub struct FileDocument {
     // even if it exists in the database and storage
     // (e.g., flagged for abuse)
     pub hidden: bool,
+
+    // Set for files that could be decoded as images
+    #[serde(default)]
+    pub image: Option<ImageMetadata>,
+    // Set for videos probed with ffprobe
+    #[serde(default)]
+    pub media: Option<MediaMetadata>,
+    // Set for audio files that could be demuxed
+    #[serde(default)]
+    pub audio: Option<AudioMetadata>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageMetadata {
+    // Dimensions as displayed, after applying the EXIF orientation
+    pub width: u32,
+    pub height: u32,
+    pub animated: bool,
+    pub frame_count: u32,
+    // Pixel layout of the source, e.g. `rgba8`
+    pub color_type: String,
+    // Most common color as `#rrggbb`, ignoring transparent pixels
+    pub dominant_color: Option<String>,
+    // Placeholders to show while the image loads, ThumbHash base64 encoded
+    #[serde(default)]
+    pub blurhash: Option<String>,
+    #[serde(default)]
+    pub thumbhash: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MediaMetadata {
+    pub duration_ms: Option<u64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub video_codec: Option<String>,
+    pub audio_codec: Option<String>,
+    // SHA-256 of the poster frame, referencing a DerivativeDocument
+    pub poster_hash: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AudioMetadata {
+    pub duration_ms: Option<u64>,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u16>,
+    // Peak levels of equal slices of the audio, scaled so the loudest is 255
+    pub waveform: Option<Vec<u8>>,
+    // Why `waveform` is not set, if it isn't
+    #[serde(default)]
+    pub waveform_unavailable: Option<WaveformUnavailable>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WaveformUnavailable {
+    // No decoder is built in for the codec, e.g. Opus from MediaRecorder
+    UnsupportedCodec,
+    // Too long to decode within the limits
+    TooLong,
 }
 
 impl FileDocument {
@@ -54,6 +154,7 @@ impl FileDocument {
         content_type: String,
         size: u64,
         user_id: String,
+        blob: String,
     ) -> Self {
         let secret_key: String = rand::rng()
             .sample_iter(&Alphanumeric)
@@ -69,9 +170,20 @@ impl FileDocument {
             uploaded_at: DateTime::now(),
             user_id,
             signing_key: secret_key,
+            blob: Some(blob),
             linked: false,
             linked_at: None,
             hidden: false,
+            image: None,
+            media: None,
+            audio: None,
+        }
+    }
+
+    pub fn object_key(&self) -> String {
+        match &self.blob {
+            Some(hash) => blob::blob_key(hash),
+            None => self.id.clone(),
         }
     }
 }
@@ -96,7 +208,7 @@ impl FileRepository {
         Ok(result)
     }
 
-    pub async fn find_expired_files() -> Result<Vec<FileDocument>> {
+    pub async fn find_expired_files(limit: i64) -> Result<Vec<FileDocument>> {
         let cutoff_time = get_time_millis() as i64 - &*FILE_TIMEOUT_HOURS * 3600 * 1000;
         let cutoff = DateTime::from_millis(cutoff_time);
         let filter = doc! {
@@ -111,7 +223,7 @@ impl FileRepository {
                 },
             ]
         };
-        let mut cursor = Self::get_collection().find(filter).await?;
+        let mut cursor = Self::get_collection().find(filter).limit(limit).await?;
         let mut expired_files = Vec::new();
         while let Some(result) = cursor.next().await {
             match result {
@@ -122,12 +234,822 @@ impl FileRepository {
         Ok(expired_files)
     }
 
-    pub async fn delete_file(id: &str) -> Result<()> {
-        Self::get_collection().delete_one(doc! { "id": id }).await?;
+    /// Lists the object of every file, reading only the fields the key is
+    /// derived from. Unreadable documents fail the listing, since skipping
+    /// one would make its object look orphaned.
+    pub async fn find_all_file_objects() -> Result<Vec<ObjectReference>> {
+        #[derive(Deserialize)]
+        struct FileObject {
+            id: String,
+            blob: Option<String>,
+            uploaded_at: DateTime,
+        }
+
+        let mut cursor = Self::get_collection()
+            .clone_with_type::<FileObject>()
+            .find(doc! {})
+            .projection(doc! { "id": 1, "blob": 1, "uploaded_at": 1 })
+            .await?;
+        let mut objects = Vec::new();
+        while let Some(file) = cursor.next().await {
+            let file = file?;
+            objects.push(ObjectReference {
+                key: match &file.blob {
+                    Some(hash) => blob::blob_key(hash),
+                    None => file.id.clone(),
+                },
+                id: file.id,
+                created_at: file.uploaded_at,
+            });
+        }
+        Ok(objects)
+    }
+
+    /// Returns those of `hashes` that are the poster of a video file.
+    pub async fn find_referenced_posters(hashes: &[String]) -> Result<HashSet<String>> {
+        let posters = Self::get_collection()
+            .distinct(
+                "media.poster_hash",
+                doc! { "media.poster_hash": { "$in": hashes } },
+            )
+            .await?;
+        Ok(posters
+            .into_iter()
+            .filter_map(|poster| poster.as_str().map(str::to_string))
+            .collect())
+    }
+
+    /// Deletes a file document, returning whether this call removed it.
+    pub async fn delete_file(id: &str) -> Result<bool> {
+        let result = Self::get_collection().delete_one(doc! { "id": id }).await?;
+        Ok(result.deleted_count == 1)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlobState {
+    // Referenced, but no upload of the object has succeeded yet
+    Pending,
+    // Blobs created before states were tracked were only inserted once stored
+    #[default]
+    Stored,
+    // The last reference was released and the object is being deleted
+    Deleting,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlobDocument {
+    // SHA-256 of the content, hex encoded
+    pub hash: String,
+    pub size: u64,
+    // Number of FileDocuments pointing to this blob
+    pub ref_count: i64,
+    #[serde(default)]
+    pub state: BlobState,
+    pub created_at: DateTime,
+    // Set when the blob enters the deleting state
+    #[serde(default)]
+    pub deleting_at: Option<DateTime>,
+}
+
+#[derive(Clone)]
+pub struct BlobRepository {}
+
+impl BlobRepository {
+    pub fn get_collection() -> Collection<BlobDocument> {
+        let client = DATABASE.get().expect("MongoDB client not initialized");
+        let db = client.database(&MONGODB_DATABASE);
+        db.collection::<BlobDocument>("blobs")
+    }
+
+    pub async fn create_indexes() -> Result<()> {
+        let index = IndexModel::builder()
+            .keys(doc! { "hash": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        Self::get_collection().create_index(index).await?;
+        Ok(())
+    }
+
+    /// Lists the object of every blob. Unreadable documents fail the listing.
+    pub async fn find_all_blob_objects() -> Result<Vec<ObjectReference>> {
+        #[derive(Deserialize)]
+        struct BlobObject {
+            hash: String,
+            created_at: DateTime,
+        }
+
+        let mut cursor = Self::get_collection()
+            .clone_with_type::<BlobObject>()
+            .find(doc! {})
+            .projection(doc! { "hash": 1, "created_at": 1 })
+            .await?;
+        let mut objects = Vec::new();
+        while let Some(blob) = cursor.next().await {
+            let blob = blob?;
+            objects.push(ObjectReference {
+                key: blob::blob_key(&blob.hash),
+                id: blob.hash,
+                created_at: blob.created_at,
+            });
+        }
+        Ok(objects)
+    }
+
+    /// Returns those of `hashes` that have a blob document.
+    pub async fn find_existing(hashes: &[String]) -> Result<HashSet<String>> {
+        let existing = Self::get_collection()
+            .distinct("hash", doc! { "hash": { "$in": hashes } })
+            .await?;
+        Ok(existing
+            .into_iter()
+            .filter_map(|value| value.as_str().map(str::to_string))
+            .collect())
+    }
+
+    /// Adds a reference to the blob, creating it in the pending state if
+    /// necessary. Returns the state before the reference was added, None if
+    /// the blob did not exist. Blobs that are being deleted are reported as
+    /// such without adding a reference.
+    pub async fn acquire(hash: &str, size: u64) -> Result<Option<BlobState>> {
+        let result = Self::get_collection()
+            .find_one_and_update(
+                doc! { "hash": hash, "state": { "$ne": "deleting" } },
+                doc! {
+                    "$inc": { "ref_count": 1 },
+                    "$setOnInsert": {
+                        "size": size as i64,
+                        "state": "pending",
+                        "created_at": DateTime::now(),
+                    },
+                },
+            )
+            .upsert(true)
+            .return_document(ReturnDocument::Before)
+            .await;
+        match result {
+            Ok(previous) => Ok(previous.map(|blob| blob.state)),
+            // The upsert collided with a blob in the deleting state
+            Err(e) if is_duplicate_key_error(&e) => Ok(Some(BlobState::Deleting)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Marks the blob as stored once its object has been uploaded.
+    pub async fn mark_stored(hash: &str) -> Result<()> {
+        Self::get_collection()
+            .update_one(
+                doc! { "hash": hash, "state": "pending" },
+                doc! { "$set": { "state": "stored" } },
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Removes a reference to the blob.
+    /// Returns true if this was the last reference, in which case the blob
+    /// is moved to the deleting state and the caller must delete its object
+    /// and then call `remove`. The move only happens while no reference has
+    /// been added in the meantime, and stops new references until `remove`.
+    pub async fn release(hash: &str) -> Result<bool> {
+        let updated = Self::get_collection()
+            .find_one_and_update(doc! { "hash": hash }, doc! { "$inc": { "ref_count": -1 } })
+            .return_document(ReturnDocument::After)
+            .await?;
+        match updated {
+            Some(blob) if blob.ref_count <= 0 => {
+                let result = Self::get_collection()
+                    .update_one(
+                        doc! { "hash": hash, "ref_count": { "$lte": 0 }, "state": { "$ne": "deleting" } },
+                        doc! { "$set": { "state": "deleting", "deleting_at": DateTime::now() } },
+                    )
+                    .await?;
+                Ok(result.modified_count > 0)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Deletes a blob in the deleting state after its object was deleted.
+    pub async fn remove(hashes: &[String]) -> Result<()> {
+        Self::get_collection()
+            .delete_many(doc! {
+                "hash": { "$in": hashes },
+                "state": "deleting",
+                "ref_count": { "$lte": 0 },
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Finds blobs that entered the deleting state before `cutoff` and were
+    /// never removed, e.g. because deleting their object failed.
+    pub async fn find_stale_deleting(cutoff: DateTime) -> Result<Vec<String>> {
+        let mut cursor = Self::get_collection()
+            .find(doc! { "state": "deleting", "deleting_at": { "$lt": cutoff } })
+            .await?;
+        let mut hashes = Vec::new();
+        while let Some(blob) = cursor.next().await {
+            hashes.push(blob?.hash);
+        }
+        Ok(hashes)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScanVerdictDocument {
+    // SHA-256 of the scanned content, hex encoded
+    pub hash: String,
+    // ClamAV signature database version that produced this verdict
+    pub definitions_version: String,
+    pub clean: bool,
+    #[serde(default)]
+    pub signatures: Vec<String>,
+    pub scanned_at: DateTime,
+}
+
+impl ScanVerdictDocument {
+    pub fn new(
+        hash: String,
+        definitions_version: String,
+        clean: bool,
+        signatures: Vec<String>,
+    ) -> Self {
+        Self {
+            hash,
+            definitions_version,
+            clean,
+            signatures,
+            scanned_at: DateTime::now(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ScanVerdictRepository {}
+
+impl ScanVerdictRepository {
+    pub fn get_collection() -> Collection<ScanVerdictDocument> {
+        let client = DATABASE.get().expect("MongoDB client not initialized");
+        let db = client.database(&MONGODB_DATABASE);
+        db.collection::<ScanVerdictDocument>("scan_verdicts")
+    }
+
+    pub async fn create_indexes() -> Result<()> {
+        let index = IndexModel::builder()
+            .keys(doc! { "hash": 1, "definitions_version": 1 })
+            .build();
+        Self::get_collection().create_index(index).await?;
+        Ok(())
+    }
+
+    pub async fn find_verdict(
+        hash: &str,
+        definitions_version: &str,
+    ) -> Result<Option<ScanVerdictDocument>> {
+        let result = Self::get_collection()
+            .find_one(doc! { "hash": hash, "definitions_version": definitions_version })
+            .await?;
+        Ok(result)
+    }
+
+    pub async fn find_infected(hash: &str) -> Result<Option<ScanVerdictDocument>> {
+        let result = Self::get_collection()
+            .find_one(doc! { "hash": hash, "clean": false })
+            .await?;
+        Ok(result)
+    }
+
+    pub async fn save_verdict(verdict: ScanVerdictDocument) -> Result<()> {
+        Self::get_collection()
+            .replace_one(
+                doc! { "hash": &verdict.hash, "definitions_version": &verdict.definitions_version },
+                verdict,
+            )
+            .upsert(true)
+            .await?;
         Ok(())
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SecurityEventDocument {
+    pub id: String,
+    // Machine-readable event kind, e.g. "malware_detected"
+    pub kind: String,
+    pub user_id: String,
+    pub hash: String,
+    pub signatures: Vec<String>,
+    pub file_name: Option<String>,
+    pub content_type: String,
+    pub size: u64,
+    pub created_at: DateTime,
+}
+
+impl SecurityEventDocument {
+    pub fn new(
+        kind: &str,
+        user_id: String,
+        hash: String,
+        signatures: Vec<String>,
+        file_name: Option<String>,
+        content_type: String,
+        size: u64,
+    ) -> Self {
+        Self {
+            id: Ulid::new().to_string(),
+            kind: kind.to_string(),
+            user_id,
+            hash,
+            signatures,
+            file_name,
+            content_type,
+            size,
+            created_at: DateTime::now(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SecurityEventRepository {}
+
+impl SecurityEventRepository {
+    pub fn get_collection() -> Collection<SecurityEventDocument> {
+        let client = DATABASE.get().expect("MongoDB client not initialized");
+        let db = client.database(&MONGODB_DATABASE);
+        db.collection::<SecurityEventDocument>("security_events")
+    }
+
+    pub async fn insert_event(event: SecurityEventDocument) -> Result<()> {
+        Self::get_collection().insert_one(event).await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LockDocument {
+    pub name: String,
+    // Instance currently holding the lease
+    pub holder: String,
+    pub expires_at: DateTime,
+}
+
+#[derive(Clone)]
+pub struct LockRepository {}
+
+impl LockRepository {
+    pub fn get_collection() -> Collection<LockDocument> {
+        let client = DATABASE.get().expect("MongoDB client not initialized");
+        let db = client.database(&MONGODB_DATABASE);
+        db.collection::<LockDocument>("locks")
+    }
+
+    pub async fn create_indexes() -> Result<()> {
+        let index = IndexModel::builder()
+            .keys(doc! { "name": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        Self::get_collection().create_index(index).await?;
+        Ok(())
+    }
+
+    /// Acquires or renews the lease if it is free, expired or already held
+    /// by `holder`. Returns false if another holder owns the lease.
+    pub async fn try_acquire(name: &str, holder: &str, lease_seconds: u64) -> Result<bool> {
+        let now = get_time_millis() as i64;
+        let expires_at = DateTime::from_millis(now + lease_seconds as i64 * 1000);
+        let filter = doc! {
+            "name": name,
+            "$or": [
+                { "expires_at": { "$lt": DateTime::from_millis(now) } },
+                { "holder": holder },
+            ]
+        };
+        let update = doc! { "$set": { "holder": holder, "expires_at": expires_at } };
+        match Self::get_collection()
+            .update_one(filter, update)
+            .upsert(true)
+            .await
+        {
+            Ok(_) => Ok(true),
+            // The upsert collides with the unique index when someone else holds the lease
+            Err(e) if is_duplicate_key_error(&e) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub async fn release(name: &str, holder: &str) -> Result<()> {
+        Self::get_collection()
+            .delete_one(doc! { "name": name, "holder": holder })
+            .await?;
+        Ok(())
+    }
+}
+
+fn is_duplicate_key_error(error: &mongodb::error::Error) -> bool {
+    matches!(
+        *error.kind,
+        ErrorKind::Write(WriteFailure::WriteError(ref e)) if e.code == 11000
+    )
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobRunDocument {
+    pub id: String,
+    pub job: String,
+    pub instance: String,
+    pub started_at: DateTime,
+    pub finished_at: Option<DateTime>,
+    pub deleted_count: u64,
+    pub errors: Vec<String>,
+    // Inconsistencies found by the job that need manual attention
+    #[serde(default)]
+    pub flagged: Vec<String>,
+}
+
+impl JobRunDocument {
+    pub fn new(job: &str, instance: String) -> Self {
+        Self {
+            id: Ulid::new().to_string(),
+            job: job.to_string(),
+            instance,
+            started_at: DateTime::now(),
+            finished_at: None,
+            deleted_count: 0,
+            errors: Vec::new(),
+            flagged: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct JobRunRepository {}
+
+impl JobRunRepository {
+    pub fn get_collection() -> Collection<JobRunDocument> {
+        let client = DATABASE.get().expect("MongoDB client not initialized");
+        let db = client.database(&MONGODB_DATABASE);
+        db.collection::<JobRunDocument>("jobs")
+    }
+
+    pub async fn insert_run(run: JobRunDocument) -> Result<()> {
+        Self::get_collection().insert_one(run).await?;
+        Ok(())
+    }
+
+    pub async fn finish_run(
+        id: &str,
+        deleted_count: u64,
+        errors: &[String],
+        flagged: &[String],
+    ) -> Result<()> {
+        Self::get_collection()
+            .update_one(
+                doc! { "id": id },
+                doc! { "$set": {
+                    "finished_at": DateTime::now(),
+                    "deleted_count": deleted_count as i64,
+                    "errors": errors,
+                    "flagged": flagged,
+                } },
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PreviewCacheDocument {
+    // Normalized URL of the previewed page
+    pub url: String,
+    pub entry: CachedPreview,
+    // Removed by the TTL index once passed
+    pub expires_at: DateTime,
+}
+
+#[derive(Clone)]
+pub struct PreviewCacheRepository {}
+
+impl PreviewCacheRepository {
+    pub fn get_collection() -> Collection<PreviewCacheDocument> {
+        let client = DATABASE.get().expect("MongoDB client not initialized");
+        let db = client.database(&MONGODB_DATABASE);
+        db.collection::<PreviewCacheDocument>("preview_cache")
+    }
+
+    pub async fn create_indexes() -> Result<()> {
+        let url_index = IndexModel::builder()
+            .keys(doc! { "url": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        let ttl_index = IndexModel::builder()
+            .keys(doc! { "expires_at": 1 })
+            .options(IndexOptions::builder().expire_after(Duration::ZERO).build())
+            .build();
+        Self::get_collection()
+            .create_indexes([url_index, ttl_index])
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_entry(url: &str) -> Result<Option<PreviewCacheDocument>> {
+        // The TTL monitor only runs periodically, so expired entries may linger
+        let result = Self::get_collection()
+            .find_one(doc! { "url": url, "expires_at": { "$gt": DateTime::now() } })
+            .await?;
+        Ok(result)
+    }
+
+    pub async fn save_entry(entry: PreviewCacheDocument) -> Result<()> {
+        Self::get_collection()
+            .replace_one(doc! { "url": &entry.url }, entry)
+            .upsert(true)
+            .await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DerivativeDocument {
+    // SHA-256 of the generated content, hex encoded
+    pub hash: String,
+    pub content_type: String,
+    pub size: u64,
+    pub created_at: DateTime,
+    // Updated when the derivative is stored again or handed out from the
+    // preview cache. Missing on derivatives stored before it was tracked.
+    #[serde(default)]
+    pub last_used_at: Option<DateTime>,
+}
+
+impl DerivativeDocument {
+    pub fn new(hash: String, content_type: String, size: u64) -> Self {
+        let now = DateTime::now();
+        Self {
+            hash,
+            content_type,
+            size,
+            created_at: now,
+            last_used_at: Some(now),
+        }
+    }
+}
+
+// Matches derivatives last used before `cutoff`, falling back to the
+// creation time for derivatives without a last use
+fn unused_derivative_filter(cutoff: DateTime) -> Document {
+    doc! {
+        "$or": [
+            { "last_used_at": { "$lt": cutoff } },
+            { "last_used_at": null, "created_at": { "$lt": cutoff } },
+        ]
+    }
+}
+
+#[derive(Clone)]
+pub struct DerivativeRepository {}
+
+impl DerivativeRepository {
+    pub fn get_collection() -> Collection<DerivativeDocument> {
+        let client = DATABASE.get().expect("MongoDB client not initialized");
+        let db = client.database(&MONGODB_DATABASE);
+        db.collection::<DerivativeDocument>("derivatives")
+    }
+
+    pub async fn create_indexes() -> Result<()> {
+        let index = IndexModel::builder()
+            .keys(doc! { "hash": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        Self::get_collection().create_index(index).await?;
+        Ok(())
+    }
+
+    pub async fn find_derivative(hash: &str) -> Result<Option<DerivativeDocument>> {
+        let result = Self::get_collection()
+            .find_one(doc! { "hash": hash })
+            .await?;
+        Ok(result)
+    }
+
+    /// Lists the object of every derivative. Unreadable documents fail the
+    /// listing.
+    pub async fn find_all_derivative_objects() -> Result<Vec<ObjectReference>> {
+        #[derive(Deserialize)]
+        struct DerivativeObject {
+            hash: String,
+            created_at: DateTime,
+        }
+
+        let mut cursor = Self::get_collection()
+            .clone_with_type::<DerivativeObject>()
+            .find(doc! {})
+            .projection(doc! { "hash": 1, "created_at": 1 })
+            .await?;
+        let mut objects = Vec::new();
+        while let Some(derivative) = cursor.next().await {
+            let derivative = derivative?;
+            objects.push(ObjectReference {
+                key: derivative::derivative_key(&derivative.hash),
+                id: derivative.hash,
+                created_at: derivative.created_at,
+            });
+        }
+        Ok(objects)
+    }
+
+    /// Returns those of `hashes` that have a derivative document.
+    pub async fn find_existing(hashes: &[String]) -> Result<HashSet<String>> {
+        let existing = Self::get_collection()
+            .distinct("hash", doc! { "hash": { "$in": hashes } })
+            .await?;
+        Ok(existing
+            .into_iter()
+            .filter_map(|value| value.as_str().map(str::to_string))
+            .collect())
+    }
+
+    /// Records a use of the derivative. Returns false if it does not exist.
+    pub async fn touch(hash: &str) -> Result<bool> {
+        let result = Self::get_collection()
+            .update_one(
+                doc! { "hash": hash },
+                doc! { "$set": { "last_used_at": DateTime::now() } },
+            )
+            .await?;
+        Ok(result.matched_count > 0)
+    }
+
+    /// Finds the hashes of derivatives not used since `cutoff`.
+    pub async fn find_unused(cutoff: DateTime) -> Result<Vec<String>> {
+        #[derive(Deserialize)]
+        struct DerivativeHash {
+            hash: String,
+        }
+
+        let mut cursor = Self::get_collection()
+            .clone_with_type::<DerivativeHash>()
+            .find(unused_derivative_filter(cutoff))
+            .projection(doc! { "hash": 1 })
+            .await?;
+        let mut hashes = Vec::new();
+        while let Some(derivative) = cursor.next().await {
+            hashes.push(derivative?.hash);
+        }
+        Ok(hashes)
+    }
+
+    /// Deletes the derivative unless it was used since `cutoff`. Returns true
+    /// if it was deleted, in which case the caller must delete its object.
+    pub async fn delete_unused(hash: &str, cutoff: DateTime) -> Result<bool> {
+        let mut filter = unused_derivative_filter(cutoff);
+        filter.insert("hash", hash);
+        let result = Self::get_collection().delete_one(filter).await?;
+        Ok(result.deleted_count > 0)
+    }
+
+    pub async fn save_derivative(derivative: DerivativeDocument) -> Result<()> {
+        match Self::get_collection().insert_one(derivative).await {
+            Ok(_) => Ok(()),
+            // Identical content stored concurrently by another request
+            Err(e) if is_duplicate_key_error(&e) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VariantDocument {
+    // Storage key, derived from the file id and normalized parameters
+    pub key: String,
+    pub file_id: String,
+    pub params: String,
+    pub content_type: String,
+    pub size: u64,
+    pub created_at: DateTime,
+}
+
+impl VariantDocument {
+    pub fn new(
+        key: String,
+        file_id: String,
+        params: String,
+        content_type: String,
+        size: u64,
+    ) -> Self {
+        Self {
+            key,
+            file_id,
+            params,
+            content_type,
+            size,
+            created_at: DateTime::now(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct VariantRepository {}
+
+impl VariantRepository {
+    pub fn get_collection() -> Collection<VariantDocument> {
+        let client = DATABASE.get().expect("MongoDB client not initialized");
+        let db = client.database(&MONGODB_DATABASE);
+        db.collection::<VariantDocument>("variants")
+    }
+
+    pub async fn create_indexes() -> Result<()> {
+        let key_index = IndexModel::builder()
+            .keys(doc! { "key": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        let file_index = IndexModel::builder().keys(doc! { "file_id": 1 }).build();
+        Self::get_collection()
+            .create_indexes([key_index, file_index])
+            .await?;
+        Ok(())
+    }
+
+    pub async fn find_variant(key: &str) -> Result<Option<VariantDocument>> {
+        let result = Self::get_collection().find_one(doc! { "key": key }).await?;
+        Ok(result)
+    }
+
+    /// Lists the object of every variant, reporting it by file id.
+    /// Unreadable documents fail the listing.
+    pub async fn find_all_variant_objects() -> Result<Vec<ObjectReference>> {
+        #[derive(Deserialize)]
+        struct VariantObject {
+            key: String,
+            file_id: String,
+            created_at: DateTime,
+        }
+
+        let mut cursor = Self::get_collection()
+            .clone_with_type::<VariantObject>()
+            .find(doc! {})
+            .projection(doc! { "key": 1, "file_id": 1, "created_at": 1 })
+            .await?;
+        let mut objects = Vec::new();
+        while let Some(variant) = cursor.next().await {
+            let variant = variant?;
+            objects.push(ObjectReference {
+                id: variant.file_id,
+                key: variant.key,
+                created_at: variant.created_at,
+            });
+        }
+        Ok(objects)
+    }
+
+    /// Returns those of `keys` that have a variant document.
+    pub async fn find_existing(keys: &[String]) -> Result<HashSet<String>> {
+        let existing = Self::get_collection()
+            .distinct("key", doc! { "key": { "$in": keys } })
+            .await?;
+        Ok(existing
+            .into_iter()
+            .filter_map(|value| value.as_str().map(str::to_string))
+            .collect())
+    }
+
+    /// Returns the storage keys of the variants of the given files.
+    pub async fn find_variant_keys_for_files(file_ids: &[String]) -> Result<Vec<String>> {
+        #[derive(Deserialize)]
+        struct VariantKey {
+            key: String,
+        }
+
+        let mut cursor = Self::get_collection()
+            .clone_with_type::<VariantKey>()
+            .find(doc! { "file_id": { "$in": file_ids } })
+            .projection(doc! { "key": 1 })
+            .await?;
+        let mut keys = Vec::new();
+        while let Some(variant) = cursor.next().await {
+            keys.push(variant?.key);
+        }
+        Ok(keys)
+    }
+
+    pub async fn save_variant(variant: VariantDocument) -> Result<()> {
+        match Self::get_collection().insert_one(variant).await {
+            Ok(_) => Ok(()),
+            // The same variant generated concurrently by another request
+            Err(e) if is_duplicate_key_error(&e) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub async fn delete_variants(keys: &[String]) -> Result<u64> {
+        let result = Self::get_collection()
+            .delete_many(doc! { "key": { "$in": keys } })
+            .await?;
+        Ok(result.deleted_count)
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Session {
     pub id: String,
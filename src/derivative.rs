@@ -0,0 +1,63 @@
+use anyhow::{Context, Result};
+use log::{debug, info};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    database::{DerivativeDocument, DerivativeRepository},
+    environment::{S3_BUCKET, SIGNING_SECRET},
+    signature, storage,
+};
+
+pub fn derivative_key(hash: &str) -> String {
+    format!("derivatives/{}", hash)
+}
+
+/// Returns the storage key of a processed version of a file. `params` must be
+/// normalized so equivalent requests map to the same variant.
+pub fn variant_key(file_id: &str, params: &str) -> String {
+    format!(
+        "variants/{}/{}",
+        file_id,
+        hex::encode(Sha256::digest(params.as_bytes()))
+    )
+}
+
+/// Stores content generated by the CDN, such as resized images, under its
+/// SHA-256 hash and returns the hash. Identical content is uploaded once, and
+/// storing it again counts as a use.
+pub async fn store(data: &[u8], content_type: &str) -> Result<String> {
+    let hash = hex::encode(Sha256::digest(data));
+    if DerivativeRepository::touch(&hash)
+        .await
+        .context("Failed to look up derivative")?
+    {
+        debug!("Derivative {} already stored, skipping upload", hash);
+        return Ok(hash);
+    }
+
+    S3_BUCKET
+        .put_object_with_content_type(derivative_key(&hash), data, content_type)
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(storage::check_response)
+        .context("Failed to upload derivative to S3")?;
+    let document =
+        DerivativeDocument::new(hash.clone(), content_type.to_string(), data.len() as u64);
+    DerivativeRepository::save_derivative(document)
+        .await
+        .context("Failed to save derivative")?;
+    info!("Stored derivative {} ({} bytes)", hash, data.len());
+    Ok(hash)
+}
+
+/// Returns a URL serving the derivative, signed with the server secret so it
+/// can be handed out without a per-file signing key. Returns None if no
+/// secret is configured.
+pub fn signed_url(hash: &str) -> Option<String> {
+    let secret = SIGNING_SECRET.as_deref()?;
+    let (signature, timestamp) = signature::generate_signature(hash, secret);
+    Some(format!(
+        "/derivatives/{}?signature={}&timestamp={}",
+        hash, signature, timestamp
+    ))
+}
@@ -29,6 +29,11 @@ lazy_static! {
         .unwrap_or_else(|_| "3310".to_string())
         .parse::<u16>()
         .expect("CLAMAV_PORT must be a valid port number");
+    pub static ref CLAMAV_VERSION_CACHE_SECONDS: u64 =
+        std::env::var("CLAMAV_VERSION_CACHE_SECONDS")
+            .unwrap_or_else(|_| "300".to_string())
+            .parse::<u64>()
+            .expect("CLAMAV_VERSION_CACHE_SECONDS must be a valid number");
     pub static ref BIND_ADDRESS: String =
         std::env::var("BIND_ADDRESS").unwrap_or_else(|_| "127.0.0.1:8080".to_string());
     pub static ref MONGODB_URI: String =
@@ -41,8 +46,186 @@ lazy_static! {
         .unwrap_or_else(|_| "3".to_string())
         .parse::<i64>()
         .expect("FILE_TIMEOUT_HOURS must be a valid number");
+    // Removes EXIF, XMP and other metadata from uploaded images
+    pub static ref UPLOAD_STRIP_METADATA: bool = std::env::var("UPLOAD_STRIP_METADATA")
+        .unwrap_or_else(|_| "true".to_string())
+        .parse::<bool>()
+        .expect("UPLOAD_STRIP_METADATA must be true or false");
+    pub static ref CLEANUP_INTERVAL_SECONDS: u64 = std::env::var("CLEANUP_INTERVAL_SECONDS")
+        .unwrap_or_else(|_| "1800".to_string())
+        .parse::<u64>()
+        .expect("CLEANUP_INTERVAL_SECONDS must be a valid number");
+    pub static ref CLEANUP_BATCH_SIZE: usize = std::env::var("CLEANUP_BATCH_SIZE")
+        .unwrap_or_else(|_| "500".to_string())
+        .parse::<usize>()
+        .ok()
+        .filter(|size| (1..=1000).contains(size))
+        .expect("CLEANUP_BATCH_SIZE must be a number between 1 and 1000");
+    pub static ref RECONCILE_INTERVAL_SECONDS: u64 = std::env::var("RECONCILE_INTERVAL_SECONDS")
+        .unwrap_or_else(|_| "86400".to_string())
+        .parse::<u64>()
+        .expect("RECONCILE_INTERVAL_SECONDS must be a valid number");
+    pub static ref RECONCILE_GRACE_HOURS: i64 = std::env::var("RECONCILE_GRACE_HOURS")
+        .unwrap_or_else(|_| "24".to_string())
+        .parse::<i64>()
+        .expect("RECONCILE_GRACE_HOURS must be a valid number");
+    pub static ref RECONCILE_DELETE_ORPHANS: bool = std::env::var("RECONCILE_DELETE_ORPHANS")
+        .unwrap_or_else(|_| "false".to_string())
+        .parse::<bool>()
+        .expect("RECONCILE_DELETE_ORPHANS must be true or false");
+    pub static ref JOB_LEASE_SECONDS: u64 = std::env::var("JOB_LEASE_SECONDS")
+        .unwrap_or_else(|_| "300".to_string())
+        .parse::<u64>()
+        .expect("JOB_LEASE_SECONDS must be a valid number");
+    pub static ref FETCH_ALLOWED_PORTS: Vec<u16> = std::env::var("FETCH_ALLOWED_PORTS")
+        .unwrap_or_else(|_| "80,443".to_string())
+        .split(',')
+        .map(|port| {
+            port.trim()
+                .parse::<u16>()
+                .expect("FETCH_ALLOWED_PORTS must be a list of valid port numbers")
+        })
+        .collect();
+    // Hosts (and their subdomains) that may be fetched even if they resolve to private addresses
+    pub static ref FETCH_ALLOWED_HOSTS: Vec<String> = parse_list("FETCH_ALLOWED_HOSTS");
+    // Hosts (and their subdomains) that are never fetched
+    pub static ref FETCH_DENIED_HOSTS: Vec<String> = parse_list("FETCH_DENIED_HOSTS");
+    pub static ref FETCH_MAX_HTML_BYTES: usize = std::env::var("FETCH_MAX_HTML_BYTES")
+        .unwrap_or_else(|_| "2097152".to_string())
+        .parse::<usize>()
+        .expect("FETCH_MAX_HTML_BYTES must be a valid number");
+    pub static ref FETCH_MAX_IMAGE_BYTES: usize = std::env::var("FETCH_MAX_IMAGE_BYTES")
+        .unwrap_or_else(|_| "20971520".to_string())
+        .parse::<usize>()
+        .expect("FETCH_MAX_IMAGE_BYTES must be a valid number");
+    pub static ref IMAGE_MAX_SOURCE_PIXELS: u64 = std::env::var("IMAGE_MAX_SOURCE_PIXELS")
+        .unwrap_or_else(|_| "40000000".to_string())
+        .parse::<u64>()
+        .expect("IMAGE_MAX_SOURCE_PIXELS must be a valid number");
+    pub static ref IMAGE_MAX_OUTPUT_DIMENSION: u32 = std::env::var("IMAGE_MAX_OUTPUT_DIMENSION")
+        .unwrap_or_else(|_| "4096".to_string())
+        .parse::<u32>()
+        .expect("IMAGE_MAX_OUTPUT_DIMENSION must be a valid number");
+    pub static ref IMAGE_MAX_DECODE_BYTES: u64 = std::env::var("IMAGE_MAX_DECODE_BYTES")
+        .unwrap_or_else(|_| "536870912".to_string())
+        .parse::<u64>()
+        .expect("IMAGE_MAX_DECODE_BYTES must be a valid number");
+    pub static ref IMAGE_MAX_FRAMES: usize = std::env::var("IMAGE_MAX_FRAMES")
+        .unwrap_or_else(|_| "500".to_string())
+        .parse::<usize>()
+        .expect("IMAGE_MAX_FRAMES must be a valid number");
+    // Pixels across all frames of an animation, before and after resizing
+    pub static ref IMAGE_MAX_ANIMATION_PIXELS: u64 = std::env::var("IMAGE_MAX_ANIMATION_PIXELS")
+        .unwrap_or_else(|_| "100000000".to_string())
+        .parse::<u64>()
+        .expect("IMAGE_MAX_ANIMATION_PIXELS must be a valid number");
+    // Images are processed on at most this many blocking threads at once
+    pub static ref IMAGE_WORKER_THREADS: usize = std::env::var("IMAGE_WORKER_THREADS")
+        .unwrap_or_else(|_| {
+            std::thread::available_parallelism()
+                .map(|threads| threads.get())
+                .unwrap_or(4)
+                .to_string()
+        })
+        .parse::<usize>()
+        .ok()
+        .filter(|threads| *threads > 0)
+        .expect("IMAGE_WORKER_THREADS must be a positive number");
+    // Media probing of video uploads is disabled unless ffprobe is configured;
+    // posters additionally need ffmpeg. Both run as the CDN's user with
+    // resource limits and protocol and format whitelists, but no further
+    // sandboxing, so point these at wrappers if stronger isolation is needed.
+    pub static ref FFPROBE_PATH: Option<String> = std::env::var("FFPROBE_PATH").ok();
+    pub static ref FFMPEG_PATH: Option<String> = std::env::var("FFMPEG_PATH").ok();
+    // ffprobe and ffmpeg processes running at once, each of which may use up
+    // to 2 GiB of address space
+    pub static ref MEDIA_WORKER_THREADS: usize = std::env::var("MEDIA_WORKER_THREADS")
+        .unwrap_or_else(|_| "2".to_string())
+        .parse::<usize>()
+        .ok()
+        .filter(|threads| *threads > 0)
+        .expect("MEDIA_WORKER_THREADS must be a positive number");
+    pub static ref MEDIA_PROBE_TIMEOUT_SECONDS: u64 = std::env::var("MEDIA_PROBE_TIMEOUT_SECONDS")
+        .unwrap_or_else(|_| "10".to_string())
+        .parse::<u64>()
+        .ok()
+        .filter(|seconds| *seconds > 0)
+        .expect("MEDIA_PROBE_TIMEOUT_SECONDS must be a positive number");
+    pub static ref PREVIEW_CACHE_CAPACITY: usize = std::env::var("PREVIEW_CACHE_CAPACITY")
+        .unwrap_or_else(|_| "1000".to_string())
+        .parse::<usize>()
+        .expect("PREVIEW_CACHE_CAPACITY must be a valid number");
+    pub static ref PREVIEW_CACHE_MIN_SECONDS: u64 = std::env::var("PREVIEW_CACHE_MIN_SECONDS")
+        .unwrap_or_else(|_| "300".to_string())
+        .parse::<u64>()
+        .expect("PREVIEW_CACHE_MIN_SECONDS must be a valid number");
+    pub static ref PREVIEW_CACHE_MAX_SECONDS: u64 = std::env::var("PREVIEW_CACHE_MAX_SECONDS")
+        .unwrap_or_else(|_| "86400".to_string())
+        .parse::<u64>()
+        .expect("PREVIEW_CACHE_MAX_SECONDS must be a valid number");
+    // Used when the page sets neither Cache-Control nor Expires
+    pub static ref PREVIEW_CACHE_DEFAULT_SECONDS: u64 =
+        std::env::var("PREVIEW_CACHE_DEFAULT_SECONDS")
+            .unwrap_or_else(|_| "3600".to_string())
+            .parse::<u64>()
+            .expect("PREVIEW_CACHE_DEFAULT_SECONDS must be a valid number");
+    pub static ref PREVIEW_CACHE_NEGATIVE_SECONDS: u64 =
+        std::env::var("PREVIEW_CACHE_NEGATIVE_SECONDS")
+            .unwrap_or_else(|_| "60".to_string())
+            .parse::<u64>()
+            .expect("PREVIEW_CACHE_NEGATIVE_SECONDS must be a valid number");
+    pub static ref PREVIEW_BATCH_MAX_URLS: usize = std::env::var("PREVIEW_BATCH_MAX_URLS")
+        .unwrap_or_else(|_| "10".to_string())
+        .parse::<usize>()
+        .expect("PREVIEW_BATCH_MAX_URLS must be a valid number");
+    pub static ref PREVIEW_BATCH_HOST_CONCURRENCY: usize =
+        std::env::var("PREVIEW_BATCH_HOST_CONCURRENCY")
+            .unwrap_or_else(|_| "2".to_string())
+            .parse::<usize>()
+            .ok()
+            .filter(|limit| *limit > 0)
+            .expect("PREVIEW_BATCH_HOST_CONCURRENCY must be a positive number");
+    pub static ref PREVIEW_BATCH_TIMEOUT_SECONDS: u64 =
+        std::env::var("PREVIEW_BATCH_TIMEOUT_SECONDS")
+            .unwrap_or_else(|_| "15".to_string())
+            .parse::<u64>()
+            .expect("PREVIEW_BATCH_TIMEOUT_SECONDS must be a valid number");
+    pub static ref PREVIEW_REHOST_IMAGES: bool = std::env::var("PREVIEW_REHOST_IMAGES")
+        .unwrap_or_else(|_| "false".to_string())
+        .parse::<bool>()
+        .expect("PREVIEW_REHOST_IMAGES must be true or false");
+    pub static ref PREVIEW_REHOST_MAX_WIDTH: u32 = std::env::var("PREVIEW_REHOST_MAX_WIDTH")
+        .unwrap_or_else(|_| "1200".to_string())
+        .parse::<u32>()
+        .ok()
+        .filter(|width| *width > 0)
+        .expect("PREVIEW_REHOST_MAX_WIDTH must be a positive number");
+    pub static ref PROXY_CACHE_MAX_BYTES: usize = std::env::var("PROXY_CACHE_MAX_BYTES")
+        .unwrap_or_else(|_| "67108864".to_string())
+        .parse::<usize>()
+        .expect("PROXY_CACHE_MAX_BYTES must be a valid number");
+    pub static ref PROXY_CACHE_SECONDS: u64 = std::env::var("PROXY_CACHE_SECONDS")
+        .unwrap_or_else(|_| "86400".to_string())
+        .parse::<u64>()
+        .expect("PROXY_CACHE_SECONDS must be a valid number");
+    // Replaces the built-in oEmbed provider registry
+    pub static ref OEMBED_PROVIDERS_PATH: Option<String> = std::env::var("OEMBED_PROVIDERS_PATH").ok();
+    // Signs URLs for content that is not owned by a single file. The image
+    // proxy is disabled without it, and rehosting and posters require it
+    pub static ref SIGNING_SECRET: Option<String> = std::env::var("SIGNING_SECRET")
+        .ok()
+        .filter(|secret| !secret.is_empty());
     pub static ref SIGNATURE_EXPIRY_SECONDS: u64 = std::env::var("SIGNATURE_EXPIRY_SECONDS")
         .unwrap_or_else(|_| "3600".to_string())
         .parse::<u64>()
         .expect("SIGNATURE_EXPIRY_SECONDS must be a valid number");
 }
+
+fn parse_list(name: &str) -> Vec<String> {
+    std::env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
@@ -0,0 +1,352 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use bytes::{Bytes, BytesMut};
+use lazy_static::lazy_static;
+use log::warn;
+use reqwest::{
+    Response,
+    dns::{Addrs, Name, Resolve, Resolving},
+    header::{CACHE_CONTROL, CONTENT_TYPE},
+    redirect,
+};
+use thiserror::Error;
+use url::{Host, Url};
+
+use crate::environment::{FETCH_ALLOWED_HOSTS, FETCH_ALLOWED_PORTS, FETCH_DENIED_HOSTS};
+
+const MAX_REDIRECTS: usize = 10;
+// Bytes of the previous chunk passed to `read_body_until` callbacks so that
+// markers split across chunks are still found
+const SCAN_OVERLAP: usize = 16;
+
+lazy_static! {
+    // Client for user-supplied URLs: every hop is validated by the redirect
+    // policy and every connection goes through the filtering resolver
+    pub static ref HTTP_CLIENT: reqwest::Client = {
+        reqwest::Client::builder()
+            .user_agent("Mozilla/5.0 (Linux x86_64; rv:140.0) Gecko/20100101 Firefox/140.0")
+            .timeout(std::time::Duration::from_secs(10))
+            .no_proxy()
+            .dns_resolver(Arc::new(FilteringResolver))
+            .redirect(redirect::Policy::custom(|attempt| {
+                if attempt.previous().len() >= MAX_REDIRECTS {
+                    attempt.error(FetchError::TooManyRedirects)
+                } else if let Err(e) = validate_url(attempt.url()) {
+                    attempt.error(e)
+                } else {
+                    attempt.follow()
+                }
+            }))
+            .build()
+            .expect("Failed to create HTTP client")
+    };
+}
+
+#[derive(Debug, Error)]
+pub enum FetchError {
+    #[error("Invalid URL")]
+    InvalidUrl,
+    #[error("URL scheme {0} is not allowed")]
+    SchemeNotAllowed(String),
+    #[error("Port {0} is not allowed")]
+    PortNotAllowed(u16),
+    #[error("Host {0} is not allowed")]
+    HostNotAllowed(String),
+    #[error("Address {0} is not allowed")]
+    AddressNotAllowed(IpAddr),
+    #[error("Too many redirects")]
+    TooManyRedirects,
+    #[error("Content type {0} is not allowed")]
+    ContentTypeNotAllowed(String),
+    #[error("Response is larger than {0} bytes")]
+    TooLarge(usize),
+}
+
+/// Returns the fetch policy violation behind this error, if any, so handlers
+/// can tell rejected URLs apart from upstream failures.
+pub fn rejection(error: &anyhow::Error) -> Option<&FetchError> {
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<FetchError>())
+}
+
+/// Sends a GET request to a user-supplied URL after checking it against the
+/// fetch policy.
+pub async fn get(url: &str) -> Result<Response> {
+    let url = Url::parse(url).map_err(|_| FetchError::InvalidUrl)?;
+    validate_url(&url)?;
+    let response = HTTP_CLIENT
+        .get(url)
+        .send()
+        .await
+        .context("Failed to fetch URL")?;
+    Ok(response)
+}
+
+/// Returns how long the response may be cached according to its
+/// `Cache-Control` header, or None if the header does not say.
+pub fn cache_max_age(response: &Response) -> Option<u64> {
+    let cache_control = response
+        .headers()
+        .get(CACHE_CONTROL)?
+        .to_str()
+        .ok()?
+        .to_ascii_lowercase();
+    let mut max_age = None;
+    for directive in cache_control.split(',').map(str::trim) {
+        match directive.split_once('=') {
+            None if matches!(directive, "no-store" | "no-cache" | "private") => return Some(0),
+            Some(("s-maxage", value)) => return value.trim_matches('"').parse().ok(),
+            Some(("max-age", value)) => max_age = value.trim_matches('"').parse().ok(),
+            _ => {}
+        }
+    }
+    max_age
+}
+
+/// Returns the media type of the response without parameters, e.g.
+/// `text/html` for `text/html; charset=utf-8`.
+pub fn content_type(response: &Response) -> Option<String> {
+    let content_type = response.headers().get(CONTENT_TYPE)?.to_str().ok()?;
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    (!essence.is_empty()).then_some(essence)
+}
+
+/// Returns the `charset` parameter of the response's content type, if any.
+pub fn content_type_charset(response: &Response) -> Option<String> {
+    let content_type = response.headers().get(CONTENT_TYPE)?.to_str().ok()?;
+    content_type.split(';').skip(1).find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("charset")
+            .then(|| value.trim().trim_matches('"').to_string())
+    })
+}
+
+/// Returns true if the media type is in `allowed`. Entries ending in `/`
+/// match a whole top-level type, e.g. `image/`.
+pub fn content_type_matches(content_type: &str, allowed: &[&str]) -> bool {
+    allowed.iter().any(|allowed| {
+        if allowed.ends_with('/') {
+            content_type.starts_with(allowed)
+        } else {
+            content_type == *allowed
+        }
+    })
+}
+
+/// Rejects the response if it declares a content type outside `allowed`.
+/// Responses without a content type are let through.
+pub fn check_content_type(response: &Response, allowed: &[&str]) -> Result<(), FetchError> {
+    match content_type(response) {
+        Some(content_type) if !content_type_matches(&content_type, allowed) => {
+            Err(FetchError::ContentTypeNotAllowed(content_type))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// What `read_body_until` does after reading a chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadProgress {
+    // Keep reading, failing if the limit is reached
+    Incomplete,
+    // Keep reading, but stop without failing if the limit is reached since
+    // the data read so far is usable
+    Sufficient,
+    Complete,
+}
+
+/// Reads the response body without buffering more than `max_bytes`.
+pub async fn read_body(response: Response, max_bytes: usize) -> Result<Bytes> {
+    read_body_until(response, max_bytes, |_| ReadProgress::Incomplete).await
+}
+
+/// Reads the response body until `max_bytes` have been received or `done`
+/// reports it complete. `done` is called with each new chunk plus a small
+/// overlap with the previous one. Once it reports the data sufficient,
+/// reaching `max_bytes` ends the body instead of failing.
+pub async fn read_body_until<F>(
+    mut response: Response,
+    max_bytes: usize,
+    mut done: F,
+) -> Result<Bytes>
+where
+    F: FnMut(&[u8]) -> ReadProgress,
+{
+    if let Some(length) = response.content_length()
+        && length > max_bytes as u64
+    {
+        return Err(FetchError::TooLarge(max_bytes).into());
+    }
+    let mut body = BytesMut::new();
+    let mut sufficient = false;
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .context("Failed to read response body")?
+    {
+        if body.len() + chunk.len() > max_bytes {
+            if sufficient {
+                break;
+            }
+            return Err(FetchError::TooLarge(max_bytes).into());
+        }
+        let scan_from = body.len().saturating_sub(SCAN_OVERLAP);
+        body.extend_from_slice(&chunk);
+        match done(&body[scan_from..]) {
+            ReadProgress::Incomplete => {}
+            ReadProgress::Sufficient => sufficient = true,
+            ReadProgress::Complete => break,
+        }
+    }
+    Ok(body.freeze())
+}
+
+/// Reads at most `max_bytes` from the start of the response body and drops
+/// the rest, e.g. to sniff a file header.
+pub async fn read_prefix(mut response: Response, max_bytes: usize) -> Result<Bytes> {
+    let mut body = BytesMut::new();
+    while body.len() < max_bytes
+        && let Some(chunk) = response
+            .chunk()
+            .await
+            .context("Failed to read response body")?
+    {
+        let take = chunk.len().min(max_bytes - body.len());
+        body.extend_from_slice(&chunk[..take]);
+    }
+    Ok(body.freeze())
+}
+
+fn host_matches(host: &str, patterns: &[String]) -> bool {
+    patterns.iter().any(|pattern| {
+        host.eq_ignore_ascii_case(pattern)
+            || host
+                .to_ascii_lowercase()
+                .ends_with(&format!(".{}", pattern.to_ascii_lowercase()))
+    })
+}
+
+/// Checks the scheme, port and host of a URL. Hostnames are checked against
+/// blocked address ranges when they are resolved, IP literals right away.
+pub fn validate_url(url: &Url) -> Result<(), FetchError> {
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(FetchError::SchemeNotAllowed(url.scheme().to_string()));
+    }
+    let port = url.port_or_known_default().ok_or(FetchError::InvalidUrl)?;
+    if !FETCH_ALLOWED_PORTS.contains(&port) {
+        return Err(FetchError::PortNotAllowed(port));
+    }
+    let host = url.host().ok_or(FetchError::InvalidUrl)?;
+    let host_str = host.to_string();
+    if host_matches(&host_str, &FETCH_DENIED_HOSTS) {
+        return Err(FetchError::HostNotAllowed(host_str));
+    }
+    if host_matches(&host_str, &FETCH_ALLOWED_HOSTS) {
+        return Ok(());
+    }
+    match host {
+        Host::Ipv4(ip) => validate_address(IpAddr::V4(ip)),
+        Host::Ipv6(ip) => validate_address(IpAddr::V6(ip)),
+        Host::Domain(_) => Ok(()),
+    }
+}
+
+fn validate_address(ip: IpAddr) -> Result<(), FetchError> {
+    let blocked = match ip {
+        IpAddr::V4(ip) => is_blocked_ipv4(ip),
+        IpAddr::V6(ip) => is_blocked_ipv6(ip),
+    };
+    if blocked {
+        Err(FetchError::AddressNotAllowed(ip))
+    } else {
+        Ok(())
+    }
+}
+
+fn is_blocked_ipv4(ip: Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // 0.0.0.0/8 "this network"
+        || octets[0] == 0
+        // 100.64.0.0/10 carrier-grade NAT
+        || (octets[0] == 100 && (octets[1] & 0xc0) == 64)
+        // 192.0.0.0/24 IETF protocol assignments
+        || (octets[0] == 192 && octets[1] == 0 && octets[2] == 0)
+        // 198.18.0.0/15 benchmarking
+        || (octets[0] == 198 && (octets[1] & 0xfe) == 18)
+        // 240.0.0.0/4 reserved
+        || octets[0] >= 240
+}
+
+fn is_blocked_ipv6(ip: Ipv6Addr) -> bool {
+    if let Some(ipv4) = ip.to_ipv4_mapped() {
+        return is_blocked_ipv4(ipv4);
+    }
+    let segments = ip.segments();
+    // 64:ff9b::/96 NAT64 and the deprecated ::/96 IPv4-compatible addresses
+    // embed an IPv4 address in the last 32 bits
+    if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] || segments[..6] == [0, 0, 0, 0, 0, 0] {
+        return is_blocked_ipv4(embedded_ipv4(segments[6], segments[7]));
+    }
+    // 2002::/16 6to4 embeds the IPv4 address of the site in bits 16-48
+    if segments[0] == 0x2002 {
+        return is_blocked_ipv4(embedded_ipv4(segments[1], segments[2]));
+    }
+    // 2001::/32 Teredo tunnels to an obfuscated client address, so it is
+    // blocked outright
+    if segments[0] == 0x2001 && segments[1] == 0 {
+        return true;
+    }
+    ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        || ip.is_unique_local()
+        || ip.is_unicast_link_local()
+        // 2001:db8::/32 documentation
+        || (segments[0] == 0x2001 && segments[1] == 0xdb8)
+}
+
+fn embedded_ipv4(high: u16, low: u16) -> Ipv4Addr {
+    let [a, b] = high.to_be_bytes();
+    let [c, d] = low.to_be_bytes();
+    Ipv4Addr::new(a, b, c, d)
+}
+
+/// Resolves hostnames itself so that connections can only be made to public
+/// addresses, including on redirects and for names that resolve differently
+/// between checks.
+struct FilteringResolver;
+
+impl Resolve for FilteringResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let addrs: Vec<SocketAddr> =
+                tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+            if !host_matches(&host, &FETCH_ALLOWED_HOSTS) {
+                for addr in &addrs {
+                    if let Err(e) = validate_address(addr.ip()) {
+                        warn!("Blocked fetch of {} resolving to {}", host, addr.ip());
+                        return Err(e.into());
+                    }
+                }
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
@@ -0,0 +1,273 @@
+use log::info;
+use mongodb::bson::DateTime;
+
+use crate::{
+    blob,
+    database::{
+        BlobRepository, DerivativeRepository, FileDocument, FileRepository, VariantRepository,
+    },
+    derivative,
+    environment::{CLEANUP_BATCH_SIZE, PREVIEW_CACHE_MAX_SECONDS},
+    get_time_millis,
+    jobs::{JobReport, Lease},
+    storage::{self, MAX_DELETE_KEYS},
+};
+
+pub const JOB_NAME: &str = "file_cleanup";
+// Blobs in the deleting state for longer than this are assumed abandoned
+const STALE_DELETING_MILLIS: i64 = 60 * 60 * 1000;
+
+pub async fn run(lease: Lease) -> JobReport {
+    let mut report = JobReport::default();
+    loop {
+        let expired_files =
+            match FileRepository::find_expired_files(*CLEANUP_BATCH_SIZE as i64).await {
+                Ok(files) => files,
+                Err(e) => {
+                    report.error(format!("Failed to find expired files: {}", e));
+                    break;
+                }
+            };
+        if expired_files.is_empty() {
+            break;
+        }
+        info!("Found {} expired files to delete", expired_files.len());
+
+        let deleted = delete_batch(&expired_files, &mut report).await;
+        report.deleted_count += deleted;
+        // Stop if the batch made no progress to avoid retrying the same files forever
+        if deleted == 0 || expired_files.len() < *CLEANUP_BATCH_SIZE || !lease.renew().await {
+            break;
+        }
+    }
+    delete_stale_blobs(&mut report).await;
+    delete_unused_derivatives(&mut report).await;
+    if report.deleted_count == 0 && report.errors.is_empty() {
+        info!("No expired files found");
+    } else {
+        info!("Deleted {} expired files", report.deleted_count);
+    }
+    report
+}
+
+async fn delete_batch(files: &[FileDocument], report: &mut JobReport) -> u64 {
+    // Files uploaded before deduplication own their object, so it is removed
+    // before the document to avoid leaving it untracked
+    let legacy_keys: Vec<String> = files
+        .iter()
+        .filter(|file| file.blob.is_none())
+        .map(|file| file.id.clone())
+        .collect();
+    let mut deletable: Vec<String> = files
+        .iter()
+        .filter(|file| file.blob.is_some())
+        .map(|file| file.id.clone())
+        .collect();
+    match storage::delete_objects(&legacy_keys).await {
+        Ok(failures) => {
+            for failure in &failures {
+                report.error(format!(
+                    "Failed to delete expired file {} from S3: {} {}",
+                    failure.key, failure.code, failure.message
+                ));
+            }
+            deletable.extend(
+                legacy_keys
+                    .into_iter()
+                    .filter(|key| !failures.iter().any(|failure| &failure.key == key)),
+            );
+        }
+        Err(e) => report.error(format!("Failed to delete expired files from S3: {}", e)),
+    }
+    if deletable.is_empty() {
+        return 0;
+    }
+
+    // Documents are deleted one at a time so a blob is only released by the
+    // run that actually removed the file pointing to it, even if another
+    // instance picked up the same files after the lease expired
+    let mut deleted = Vec::new();
+    let mut unreferenced = Vec::new();
+    for file in files {
+        if !deletable.contains(&file.id) {
+            continue;
+        }
+        match FileRepository::delete_file(&file.id).await {
+            Ok(true) => deleted.push(file.id.clone()),
+            Ok(false) => continue,
+            Err(e) => {
+                report.error(format!(
+                    "Failed to delete expired file {} from MongoDB: {}",
+                    file.id, e
+                ));
+                continue;
+            }
+        }
+        if let Some(hash) = &file.blob {
+            match BlobRepository::release(hash).await {
+                Ok(true) => unreferenced.push(hash.clone()),
+                Ok(false) => {}
+                Err(e) => report.error(format!("Failed to release blob {}: {}", hash, e)),
+            }
+        }
+    }
+    delete_blobs(&unreferenced, report).await;
+    delete_variants(&deleted, report).await;
+    delete_posters(files, &deleted, report).await;
+    deleted.len() as u64
+}
+
+/// Deletes the objects of blobs in the deleting state, then their documents.
+/// Blobs whose object could not be deleted stay in the deleting state and are
+/// retried once they become stale.
+async fn delete_blobs(hashes: &[String], report: &mut JobReport) {
+    for chunk in hashes.chunks(MAX_DELETE_KEYS) {
+        let keys: Vec<String> = chunk.iter().map(|hash| blob::blob_key(hash)).collect();
+        let deleted: Vec<String> = match storage::delete_objects(&keys).await {
+            Ok(failures) => {
+                for failure in &failures {
+                    report.error(format!(
+                        "Failed to delete blob {} from S3: {} {}",
+                        failure.key, failure.code, failure.message
+                    ));
+                }
+                chunk
+                    .iter()
+                    .filter(|hash| {
+                        let key = blob::blob_key(hash);
+                        !failures.iter().any(|failure| failure.key == key)
+                    })
+                    .cloned()
+                    .collect()
+            }
+            Err(e) => {
+                report.error(format!("Failed to delete blobs from S3: {}", e));
+                continue;
+            }
+        };
+        if let Err(e) = BlobRepository::remove(&deleted).await {
+            report.error(format!("Failed to remove deleted blobs: {}", e));
+        }
+    }
+}
+
+/// Finishes deleting blobs left in the deleting state, e.g. by a failed S3
+/// request or an instance that stopped midway.
+async fn delete_stale_blobs(report: &mut JobReport) {
+    let cutoff = DateTime::from_millis(get_time_millis() as i64 - STALE_DELETING_MILLIS);
+    match BlobRepository::find_stale_deleting(cutoff).await {
+        Ok(hashes) if !hashes.is_empty() => {
+            info!("Retrying deletion of {} blobs", hashes.len());
+            delete_blobs(&hashes, report).await;
+        }
+        Ok(_) => {}
+        Err(e) => report.error(format!("Failed to find stale deleting blobs: {}", e)),
+    }
+}
+
+/// Deletes derivatives that have not been used for longer than a preview can
+/// stay cached, so no cached preview still points to them.
+async fn delete_unused_derivatives(report: &mut JobReport) {
+    let cutoff =
+        DateTime::from_millis(get_time_millis() as i64 - *PREVIEW_CACHE_MAX_SECONDS as i64 * 1000);
+    let hashes = match DerivativeRepository::find_unused(cutoff).await {
+        Ok(hashes) => hashes,
+        Err(e) => {
+            report.error(format!("Failed to find unused derivatives: {}", e));
+            return;
+        }
+    };
+    let deleted_count = delete_derivatives(&hashes, cutoff, report).await;
+    if deleted_count > 0 {
+        info!("Deleted {} unused derivatives", deleted_count);
+    }
+}
+
+/// Deletes the poster frames of deleted video files, unless another file
+/// shares the poster.
+async fn delete_posters(files: &[FileDocument], deleted_ids: &[String], report: &mut JobReport) {
+    let mut hashes: Vec<String> = files
+        .iter()
+        .filter(|file| deleted_ids.contains(&file.id))
+        .filter_map(|file| file.media.as_ref()?.poster_hash.clone())
+        .collect();
+    hashes.sort();
+    hashes.dedup();
+    // Posters stored again by an upload after this point are kept
+    delete_derivatives(&hashes, DateTime::now(), report).await;
+}
+
+/// Deletes the derivatives not used since `cutoff`, skipping posters of
+/// existing files. Returns how many were deleted.
+async fn delete_derivatives(hashes: &[String], cutoff: DateTime, report: &mut JobReport) -> usize {
+    let mut deleted_count = 0;
+    for chunk in hashes.chunks(MAX_DELETE_KEYS) {
+        let posters = match FileRepository::find_referenced_posters(chunk).await {
+            Ok(posters) => posters,
+            Err(e) => {
+                report.error(format!("Failed to find referenced posters: {}", e));
+                continue;
+            }
+        };
+        // Documents are deleted first and only if still unused, so a
+        // derivative stored again in the meantime is kept
+        let mut keys = Vec::new();
+        for hash in chunk.iter().filter(|hash| !posters.contains(*hash)) {
+            match DerivativeRepository::delete_unused(hash, cutoff).await {
+                Ok(true) => keys.push(derivative::derivative_key(hash)),
+                Ok(false) => {}
+                Err(e) => report.error(format!("Failed to delete derivative {}: {}", hash, e)),
+            }
+        }
+        // Objects that fail to delete are left for orphan reconciliation
+        match storage::delete_objects(&keys).await {
+            Ok(failures) => {
+                deleted_count += keys.len() - failures.len();
+                for failure in failures {
+                    report.error(format!(
+                        "Failed to delete derivative {} from S3: {} {}",
+                        failure.key, failure.code, failure.message
+                    ));
+                }
+            }
+            Err(e) => report.error(format!("Failed to delete derivatives from S3: {}", e)),
+        }
+    }
+    deleted_count
+}
+
+/// Deletes the generated variants of deleted files. Variants whose object
+/// could not be deleted keep their document so a later run can retry.
+async fn delete_variants(file_ids: &[String], report: &mut JobReport) {
+    let keys = match VariantRepository::find_variant_keys_for_files(file_ids).await {
+        Ok(keys) => keys,
+        Err(e) => {
+            report.error(format!("Failed to find variants of expired files: {}", e));
+            return;
+        }
+    };
+    for chunk in keys.chunks(MAX_DELETE_KEYS) {
+        let deleted: Vec<String> = match storage::delete_objects(chunk).await {
+            Ok(failures) => {
+                for failure in &failures {
+                    report.error(format!(
+                        "Failed to delete variant {} from S3: {} {}",
+                        failure.key, failure.code, failure.message
+                    ));
+                }
+                chunk
+                    .iter()
+                    .filter(|key| !failures.iter().any(|failure| &failure.key == *key))
+                    .cloned()
+                    .collect()
+            }
+            Err(e) => {
+                report.error(format!("Failed to delete variants from S3: {}", e));
+                continue;
+            }
+        };
+        if let Err(e) = VariantRepository::delete_variants(&deleted).await {
+            report.error(format!("Failed to delete variants from MongoDB: {}", e));
+        }
+    }
+}
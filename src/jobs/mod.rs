@@ -0,0 +1,97 @@
+use std::future::Future;
+
+use anyhow::Result;
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use ulid::Ulid;
+
+use crate::{
+    database::{JobRunDocument, JobRunRepository, LockRepository},
+    environment::JOB_LEASE_SECONDS,
+};
+
+pub mod cleanup;
+pub mod reconcile;
+
+lazy_static! {
+    // Identifies this replica as a lease holder
+    pub static ref INSTANCE_ID: String = Ulid::new().to_string();
+}
+
+#[derive(Debug, Default)]
+pub struct JobReport {
+    pub deleted_count: u64,
+    pub errors: Vec<String>,
+    pub flagged: Vec<String>,
+}
+
+impl JobReport {
+    pub fn error(&mut self, message: String) {
+        error!("{}", message);
+        self.errors.push(message);
+    }
+
+    pub fn flag(&mut self, message: String) {
+        warn!("{}", message);
+        self.flagged.push(message);
+    }
+}
+
+pub struct Lease {
+    name: &'static str,
+}
+
+impl Lease {
+    /// Extends the lease. Returns false if it has been taken over by another
+    /// instance, in which case the job should stop.
+    pub async fn renew(&self) -> bool {
+        match LockRepository::try_acquire(self.name, &INSTANCE_ID, *JOB_LEASE_SECONDS).await {
+            Ok(true) => true,
+            Ok(false) => {
+                warn!("Lost lease for job {}", self.name);
+                false
+            }
+            Err(e) => {
+                error!("Failed to renew lease for job {}: {}", self.name, e);
+                false
+            }
+        }
+    }
+}
+
+/// Runs the job only if this instance holds the lease for it, so that a
+/// single replica executes it at a time. Every run is recorded in the jobs
+/// collection. Returns None if another instance holds the lease.
+pub async fn run_exclusive<F, Fut>(name: &'static str, job: F) -> Result<Option<JobReport>>
+where
+    F: FnOnce(Lease) -> Fut,
+    Fut: Future<Output = JobReport>,
+{
+    if !LockRepository::try_acquire(name, &INSTANCE_ID, *JOB_LEASE_SECONDS).await? {
+        info!("Job {} is running on another instance, skipping", name);
+        return Ok(None);
+    }
+
+    let run = JobRunDocument::new(name, INSTANCE_ID.clone());
+    let run_id = run.id.clone();
+    if let Err(e) = JobRunRepository::insert_run(run).await {
+        error!("Failed to record start of job {}: {}", name, e);
+    }
+
+    let report = job(Lease { name }).await;
+
+    if let Err(e) = JobRunRepository::finish_run(
+        &run_id,
+        report.deleted_count,
+        &report.errors,
+        &report.flagged,
+    )
+    .await
+    {
+        error!("Failed to record end of job {}: {}", name, e);
+    }
+    if let Err(e) = LockRepository::release(name, &INSTANCE_ID).await {
+        error!("Failed to release lease for job {}: {}", name, e);
+    }
+    Ok(Some(report))
+}
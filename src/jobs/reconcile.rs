@@ -0,0 +1,207 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+use log::{info, warn};
+use mongodb::bson::DateTime;
+
+use crate::{
+    blob,
+    database::{BlobRepository, DerivativeRepository, FileRepository, VariantRepository},
+    derivative,
+    environment::{RECONCILE_GRACE_HOURS, S3_BUCKET},
+    get_time_millis,
+    jobs::{JobReport, Lease},
+    storage::{self, MAX_DELETE_KEYS},
+};
+
+pub const JOB_NAME: &str = "orphan_reconciliation";
+
+/// Compares the bucket against the files, blobs, derivatives and variants
+/// collections. Objects
+/// without a document are reported (and deleted if `delete_orphans` is set),
+/// documents without an object are flagged. Anything younger than
+/// `RECONCILE_GRACE_HOURS` is ignored so in-flight uploads are not touched.
+pub async fn run(lease: Lease, delete_orphans: bool) -> JobReport {
+    let mut report = JobReport::default();
+    let cutoff =
+        DateTime::from_millis(get_time_millis() as i64 - *RECONCILE_GRACE_HOURS * 3600 * 1000);
+
+    // Documents are read before listing the bucket, so objects uploaded in
+    // between look orphaned but are protected by the grace period
+    let files = match FileRepository::find_all_file_objects().await {
+        Ok(files) => files,
+        Err(e) => {
+            report.error(format!("Failed to list files: {}", e));
+            return report;
+        }
+    };
+    let blobs = match BlobRepository::find_all_blob_objects().await {
+        Ok(blobs) => blobs,
+        Err(e) => {
+            report.error(format!("Failed to list blobs: {}", e));
+            return report;
+        }
+    };
+    let derivatives = match DerivativeRepository::find_all_derivative_objects().await {
+        Ok(derivatives) => derivatives,
+        Err(e) => {
+            report.error(format!("Failed to list derivatives: {}", e));
+            return report;
+        }
+    };
+    let variants = match VariantRepository::find_all_variant_objects().await {
+        Ok(variants) => variants,
+        Err(e) => {
+            report.error(format!("Failed to list variants: {}", e));
+            return report;
+        }
+    };
+    let expected_keys: HashSet<String> = files
+        .iter()
+        .chain(&blobs)
+        .chain(&derivatives)
+        .chain(&variants)
+        .map(|object| object.key.clone())
+        .collect();
+
+    let mut bucket_keys = HashSet::new();
+    let mut orphans = Vec::new();
+    let mut continuation_token = None;
+    loop {
+        let page = match S3_BUCKET
+            .list_page(String::new(), None, continuation_token, None, None)
+            .await
+        {
+            Ok((page, _)) => page,
+            Err(e) => {
+                report.error(format!("Failed to list bucket: {}", e));
+                return report;
+            }
+        };
+        for object in page.contents {
+            if !expected_keys.contains(&object.key) {
+                match DateTime::parse_rfc3339_str(&object.last_modified) {
+                    Ok(last_modified) if last_modified < cutoff => {
+                        warn!("Orphaned object {} ({} bytes)", object.key, object.size);
+                        orphans.push(object.key.clone());
+                    }
+                    Ok(_) => {}
+                    Err(e) => warn!("Invalid timestamp for object {}: {}", object.key, e),
+                }
+            }
+            bucket_keys.insert(object.key);
+        }
+        continuation_token = page.next_continuation_token;
+        if continuation_token.is_none() {
+            break;
+        }
+        if !lease.renew().await {
+            report.error("Lost lease while listing bucket".to_string());
+            return report;
+        }
+    }
+    info!(
+        "Found {} objects in bucket, {} orphaned",
+        bucket_keys.len(),
+        orphans.len()
+    );
+
+    for file in files.iter().filter(|file| file.created_at < cutoff) {
+        if !bucket_keys.contains(&file.key) {
+            report.flag(format!("File {} is missing object {}", file.id, file.key));
+        }
+    }
+    for blob in blobs.iter().filter(|blob| blob.created_at < cutoff) {
+        if !bucket_keys.contains(&blob.key) {
+            report.flag(format!("Blob {} is missing object {}", blob.id, blob.key));
+        }
+    }
+    for derivative in derivatives
+        .iter()
+        .filter(|derivative| derivative.created_at < cutoff)
+    {
+        if !bucket_keys.contains(&derivative.key) {
+            report.flag(format!(
+                "Derivative {} is missing object {}",
+                derivative.id, derivative.key
+            ));
+        }
+    }
+    for variant in variants
+        .iter()
+        .filter(|variant| variant.created_at < cutoff)
+    {
+        if !bucket_keys.contains(&variant.key) {
+            report.flag(format!(
+                "Variant of file {} is missing object {}",
+                variant.id, variant.key
+            ));
+        }
+    }
+
+    if delete_orphans {
+        for chunk in orphans.chunks(MAX_DELETE_KEYS) {
+            let chunk = match still_orphaned(chunk).await {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    report.error(format!("Failed to recheck orphaned objects: {}", e));
+                    continue;
+                }
+            };
+            match storage::delete_objects(&chunk).await {
+                Ok(failures) => {
+                    report.deleted_count += (chunk.len() - failures.len()) as u64;
+                    for failure in failures {
+                        report.error(format!(
+                            "Failed to delete orphaned object {}: {} {}",
+                            failure.key, failure.code, failure.message
+                        ));
+                    }
+                }
+                Err(e) => report.error(format!("Failed to delete orphaned objects: {}", e)),
+            }
+        }
+        info!("Deleted {} orphaned objects", report.deleted_count);
+    } else {
+        for key in orphans {
+            report.flag(format!("Object {} has no document", key));
+        }
+    }
+    report
+}
+
+/// Drops the keys that gained a blob, derivative or variant document since
+/// the bucket was listed, e.g. because the same content was stored again.
+async fn still_orphaned(keys: &[String]) -> Result<Vec<String>> {
+    let blob_hashes: Vec<String> = keys
+        .iter()
+        .filter_map(|key| key.strip_prefix("blobs/"))
+        .map(str::to_string)
+        .collect();
+    let derivative_hashes: Vec<String> = keys
+        .iter()
+        .filter_map(|key| key.strip_prefix("derivatives/"))
+        .map(str::to_string)
+        .collect();
+    let mut live: HashSet<String> = VariantRepository::find_existing(keys).await?;
+    live.extend(
+        BlobRepository::find_existing(&blob_hashes)
+            .await?
+            .iter()
+            .map(|hash| blob::blob_key(hash)),
+    );
+    live.extend(
+        DerivativeRepository::find_existing(&derivative_hashes)
+            .await?
+            .iter()
+            .map(|hash| derivative::derivative_key(hash)),
+    );
+    for key in &live {
+        info!("Object {} is referenced again, not deleting it", key);
+    }
+    Ok(keys
+        .iter()
+        .filter(|key| !live.contains(*key))
+        .cloned()
+        .collect())
+}
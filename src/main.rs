@@ -8,18 +8,33 @@ use env_logger::Env;
 use log::{error, info};
 use serde::Serialize;
 
+pub mod animation;
+pub mod audio;
 pub mod authentication;
+pub mod blob;
 pub mod clamav;
 pub mod database;
+pub mod derivative;
 pub mod environment;
+pub mod fetch;
+pub mod jobs;
+pub mod media;
+pub mod metadata;
+pub mod oembed;
+pub mod preview_cache;
 pub mod routes;
 pub mod signature;
+pub mod storage;
+pub mod text;
 
 use authentication::AuthenticationMiddleware;
-use database::FileRepository;
+use jobs::{cleanup, reconcile};
 use tokio::time::sleep;
 
-use crate::environment::{BIND_ADDRESS, CLAMAV_HOST, CLAMAV_PORT, S3_BUCKET, S3_BUCKET_NAME};
+use crate::environment::{
+    BIND_ADDRESS, CLAMAV_HOST, CLAMAV_PORT, CLEANUP_INTERVAL_SECONDS, PREVIEW_REHOST_IMAGES,
+    RECONCILE_DELETE_ORPHANS, RECONCILE_INTERVAL_SECONDS, S3_BUCKET_NAME, SIGNING_SECRET,
+};
 
 #[derive(Serialize)]
 struct ErrorResponse {
@@ -54,38 +69,76 @@ async fn main() -> std::io::Result<()> {
 
     info!("S3 bucket: {}", &*S3_BUCKET_NAME);
     info!("ClamAV: {}:{}", &*CLAMAV_HOST, &*CLAMAV_PORT);
+    // Rehosted images and posters are only reachable through URLs signed with
+    // the secret, so fail at startup rather than storing content nobody can load
+    if SIGNING_SECRET.is_none() {
+        if *PREVIEW_REHOST_IMAGES || media::posters_enabled() {
+            error!(
+                "SIGNING_SECRET must be set when PREVIEW_REHOST_IMAGES or FFMPEG_PATH is enabled"
+            );
+            std::process::exit(1);
+        }
+        info!("SIGNING_SECRET is not set, the image proxy is disabled");
+    }
+    if *PREVIEW_REHOST_IMAGES {
+        info!("Rehosting preview images");
+    }
+    if media::is_enabled() {
+        info!("Probing video uploads with ffprobe");
+    }
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("reconcile") => {
+            // Usage: cdn reconcile [--delete]
+            let delete_orphans = args.iter().any(|arg| arg == "--delete");
+            info!(
+                "Running orphan reconciliation (delete: {})...",
+                delete_orphans
+            );
+            match jobs::run_exclusive(reconcile::JOB_NAME, |lease| {
+                reconcile::run(lease, delete_orphans)
+            })
+            .await
+            {
+                Ok(Some(report)) => info!(
+                    "Reconciliation finished: {} deleted, {} flagged, {} errors",
+                    report.deleted_count,
+                    report.flagged.len(),
+                    report.errors.len()
+                ),
+                Ok(None) => info!("Reconciliation is already running on another instance"),
+                Err(e) => error!("Reconciliation failed: {}", e),
+            }
+            return Ok(());
+        }
+        Some(command) => {
+            error!("Unknown command: {}", command);
+            std::process::exit(2);
+        }
+        None => {}
+    }
 
     tokio::spawn(async move {
         loop {
-            sleep(Duration::from_secs(30 * 60)).await;
+            sleep(Duration::from_secs(*CLEANUP_INTERVAL_SECONDS)).await;
             info!("Running file cleanup task...");
-            match FileRepository::find_expired_files().await {
-                Ok(expired_files) => {
-                    if expired_files.is_empty() {
-                        info!("No expired files found");
-                    } else {
-                        info!("Found {} expired files to delete", expired_files.len());
-                        for file in expired_files {
-                            match S3_BUCKET.delete_object(&file.id).await {
-                                Ok(_) => {
-                                    info!("Deleted expired file {} from S3", file.id);
-                                    if let Err(e) = FileRepository::delete_file(&file.id).await {
-                                        error!("Failed to delete {} from MongoDB: {}", file.id, e);
-                                    }
-                                }
-                                Err(e) => {
-                                    error!(
-                                        "Failed to delete expired file {} from S3: {}",
-                                        file.id, e
-                                    );
-                                }
-                            }
-                        }
-                    }
-                }
-                Err(e) => {
-                    error!("Failed to find expired files: {}", e);
-                }
+            if let Err(e) = jobs::run_exclusive(cleanup::JOB_NAME, cleanup::run).await {
+                error!("File cleanup task failed: {}", e);
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        loop {
+            sleep(Duration::from_secs(*RECONCILE_INTERVAL_SECONDS)).await;
+            info!("Running orphan reconciliation task...");
+            if let Err(e) = jobs::run_exclusive(reconcile::JOB_NAME, |lease| {
+                reconcile::run(lease, *RECONCILE_DELETE_ORPHANS)
+            })
+            .await
+            {
+                error!("Orphan reconciliation task failed: {}", e);
             }
         }
     });
@@ -104,13 +157,31 @@ async fn main() -> std::io::Result<()> {
                 web::scope("/api")
                     .wrap(AuthenticationMiddleware)
                     .route("/upload", web::post().to(routes::upload::upload_file))
+                    .route("/files/{file_id}", web::get().to(routes::files::get_file))
+                    .route(
+                        "/files/{file_id}/url",
+                        web::get().to(routes::files::get_file_url),
+                    )
                     .route("/preview", web::get().to(routes::preview::get_link_preview))
+                    .route(
+                        "/preview/batch",
+                        web::post().to(routes::preview::get_link_preview_batch),
+                    )
+                    .route("/proxy", web::get().to(routes::proxy::get_proxy_url))
                     .route(
                         "/preview/image",
                         web::get().to(routes::preview_image::preview_image),
                     ),
             )
             .route("/files/{file_id}", web::get().to(routes::serve::serve_file))
+            .route(
+                "/proxy/{signature}/{url}",
+                web::get().to(routes::proxy::proxy_image),
+            )
+            .route(
+                "/derivatives/{hash}",
+                web::get().to(routes::serve::serve_derivative),
+            )
             .route("/", web::get().to(health_check));
         let assets_path = Path::new("./assets");
         if assets_path.exists() && assets_path.is_dir() {
@@ -0,0 +1,293 @@
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow};
+use bytes::Bytes;
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use serde::Deserialize;
+use tokio::{io::AsyncReadExt, process::Command, sync::Semaphore, time::timeout};
+
+use crate::{
+    database::MediaMetadata,
+    derivative,
+    environment::{FFMPEG_PATH, FFPROBE_PATH, MEDIA_PROBE_TIMEOUT_SECONDS, MEDIA_WORKER_THREADS},
+};
+
+// Only local files may be opened, so playlists can't pull in URLs
+const PROTOCOL_WHITELIST: &str = "file";
+// Container demuxers allowed to read uploads. Playlist and concat demuxers are
+// left out since they can reference other local files.
+const FORMAT_WHITELIST: &str = "mov,mp4,m4a,3gp,3g2,mj2,matroska,webm,avi,flv,mpegts,mpeg,ogg,asf";
+// Larger outputs are treated as a misbehaving binary
+const MAX_OUTPUT_BYTES: usize = 8 * 1024 * 1024;
+// Only the start of the error output is kept for the error message
+const MAX_ERROR_BYTES: u64 = 64 * 1024;
+// Address space the binaries may map, enough to decode 4K video
+const MAX_ADDRESS_SPACE_BYTES: u64 = 2 * 1024 * 1024 * 1024;
+const POSTER_MAX_WIDTH: u32 = 1280;
+// Seek this far into the video for the poster, at most, to skip fade-ins
+const POSTER_MAX_SEEK_MS: u64 = 1000;
+
+lazy_static! {
+    static ref MEDIA_PROBES: Semaphore = Semaphore::new(*MEDIA_WORKER_THREADS);
+}
+
+#[derive(Deserialize)]
+struct ProbeOutput {
+    #[serde(default)]
+    streams: Vec<ProbeStream>,
+    format: Option<ProbeFormat>,
+}
+
+#[derive(Deserialize)]
+struct ProbeStream {
+    codec_type: Option<String>,
+    codec_name: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    #[serde(default)]
+    disposition: ProbeDisposition,
+}
+
+#[derive(Default, Deserialize)]
+struct ProbeDisposition {
+    // Cover art is reported as a video stream
+    #[serde(default)]
+    attached_pic: u8,
+}
+
+#[derive(Deserialize)]
+struct ProbeFormat {
+    // Seconds as a decimal string
+    duration: Option<String>,
+}
+
+pub fn is_enabled() -> bool {
+    FFPROBE_PATH.is_some()
+}
+
+/// Whether poster frames are extracted from probed videos.
+pub fn posters_enabled() -> bool {
+    is_enabled() && FFMPEG_PATH.is_some()
+}
+
+/// Reads the duration, codecs and dimensions of a video with ffprobe and, if
+/// ffmpeg is configured, stores a poster frame as a derivative.
+pub async fn probe(data: &Bytes) -> Result<MediaMetadata> {
+    let ffprobe = FFPROBE_PATH
+        .as_deref()
+        .ok_or_else(|| anyhow!("FFPROBE_PATH is not set"))?;
+    let _permit = MEDIA_PROBES
+        .acquire()
+        .await
+        .context("Media probe pool closed")?;
+
+    // The upload is written to a private directory that also serves as the
+    // working directory, and is removed when `dir` is dropped
+    let dir = tempfile::Builder::new()
+        .prefix("cdn-media-")
+        .tempdir()
+        .context("Failed to create media probe directory")?;
+    let input = dir.path().join("input");
+    tokio::fs::write(&input, data)
+        .await
+        .context("Failed to write media probe input")?;
+
+    let output = run(
+        ffprobe,
+        &[
+            "-v",
+            "error",
+            "-protocol_whitelist",
+            PROTOCOL_WHITELIST,
+            "-format_whitelist",
+            FORMAT_WHITELIST,
+            "-print_format",
+            "json",
+            "-show_format",
+            "-show_streams",
+            "input",
+        ],
+        dir.path(),
+    )
+    .await?;
+    let output: ProbeOutput =
+        serde_json::from_slice(&output).context("Failed to parse ffprobe output")?;
+
+    let video = output.streams.iter().find(|stream| {
+        stream.codec_type.as_deref() == Some("video") && stream.disposition.attached_pic == 0
+    });
+    let audio = output
+        .streams
+        .iter()
+        .find(|stream| stream.codec_type.as_deref() == Some("audio"));
+    let duration_ms = output
+        .format
+        .and_then(|format| format.duration)
+        .and_then(|duration| duration.parse::<f64>().ok())
+        .filter(|duration| duration.is_finite() && *duration >= 0.0)
+        .map(|duration| (duration * 1000.0).round() as u64);
+
+    let mut metadata = MediaMetadata {
+        duration_ms,
+        width: video.and_then(|stream| stream.width),
+        height: video.and_then(|stream| stream.height),
+        video_codec: video.and_then(|stream| stream.codec_name.clone()),
+        audio_codec: audio.and_then(|stream| stream.codec_name.clone()),
+        poster_hash: None,
+    };
+    if video.is_some()
+        && let Some(ffmpeg) = FFMPEG_PATH.as_deref()
+    {
+        let poster = async {
+            let poster = extract_poster(ffmpeg, dir.path(), duration_ms).await?;
+            derivative::store(&poster, "image/jpeg").await
+        };
+        match poster.await {
+            Ok(hash) => metadata.poster_hash = Some(hash),
+            Err(e) => warn!("Failed to create poster frame: {}", e),
+        }
+    }
+    Ok(metadata)
+}
+
+/// Grabs a frame near the start of the video as a JPEG, scaled down to at
+/// most `POSTER_MAX_WIDTH` pixels wide.
+async fn extract_poster(ffmpeg: &str, dir: &Path, duration_ms: Option<u64>) -> Result<Vec<u8>> {
+    let seek_ms = duration_ms.map_or(0, |duration| (duration / 10).min(POSTER_MAX_SEEK_MS));
+    let seek = format!("{}.{:03}", seek_ms / 1000, seek_ms % 1000);
+    let scale = format!("scale='min({},iw)':-2", POSTER_MAX_WIDTH);
+    let poster = run(
+        ffmpeg,
+        &[
+            "-nostdin",
+            "-v",
+            "error",
+            "-protocol_whitelist",
+            PROTOCOL_WHITELIST,
+            "-format_whitelist",
+            FORMAT_WHITELIST,
+            "-ss",
+            &seek,
+            "-i",
+            "input",
+            "-frames:v",
+            "1",
+            "-vf",
+            &scale,
+            "-c:v",
+            "mjpeg",
+            "-q:v",
+            "3",
+            "-f",
+            "image2pipe",
+            "pipe:1",
+        ],
+        dir,
+    )
+    .await?;
+    if poster.is_empty() {
+        return Err(anyhow!("ffmpeg produced no frame"));
+    }
+    Ok(poster)
+}
+
+/// Runs a media binary with a clean environment, no input, resource limits
+/// and a time limit, killing it if the time limit is reached or it writes
+/// more than `MAX_OUTPUT_BYTES`. Returns its standard output.
+async fn run(binary: &str, args: &[&str], dir: &Path) -> Result<Vec<u8>> {
+    debug!("Running {} {}", binary, args.join(" "));
+    let mut command = Command::new(binary);
+    command
+        .args(args)
+        .current_dir(dir)
+        .env_clear()
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    // Read before forking, since the child may not allocate
+    let cpu_seconds = *MEDIA_PROBE_TIMEOUT_SECONDS;
+    // SAFETY: setrlimit is async-signal-safe and nothing is allocated
+    unsafe {
+        command.pre_exec(move || set_resource_limits(cpu_seconds));
+    }
+    let mut child = command
+        .spawn()
+        .with_context(|| format!("Failed to start {}", binary))?;
+    let mut stdout = child.stdout.take().context("Missing stdout pipe")?;
+    let mut stderr = child.stderr.take().context("Missing stderr pipe")?;
+
+    let collect = async {
+        let read_stdout = async {
+            let mut output = Vec::new();
+            (&mut stdout)
+                .take(MAX_OUTPUT_BYTES as u64 + 1)
+                .read_to_end(&mut output)
+                .await?;
+            if output.len() > MAX_OUTPUT_BYTES {
+                return Err(anyhow!("{} produced too much output", binary));
+            }
+            Ok(output)
+        };
+        let read_stderr = async {
+            let mut errors = Vec::new();
+            (&mut stderr)
+                .take(MAX_ERROR_BYTES)
+                .read_to_end(&mut errors)
+                .await?;
+            // The rest is drained so the binary never blocks on a full pipe
+            tokio::io::copy(&mut stderr, &mut tokio::io::sink()).await?;
+            Ok(errors)
+        };
+        let (output, errors) = tokio::try_join!(read_stdout, read_stderr)?;
+        let status = child.wait().await?;
+        Ok::<_, anyhow::Error>((status, output, errors))
+    };
+    let result = match timeout(Duration::from_secs(*MEDIA_PROBE_TIMEOUT_SECONDS), collect).await {
+        Ok(result) => result.with_context(|| format!("Failed to run {}", binary)),
+        Err(_) => Err(anyhow!("{} timed out", binary)),
+    };
+    let (status, output, errors) = match result {
+        Ok(result) => result,
+        Err(e) => {
+            if let Err(e) = child.kill().await {
+                warn!("Failed to kill {}: {}", binary, e);
+            }
+            return Err(e);
+        }
+    };
+    if !status.success() {
+        return Err(anyhow!(
+            "{} failed with {}: {}",
+            binary,
+            status,
+            String::from_utf8_lossy(&errors).trim()
+        ));
+    }
+    Ok(output)
+}
+
+/// Caps the address space, CPU time and written file size of a media binary.
+/// Runs in the forked child before exec.
+fn set_resource_limits(cpu_seconds: u64) -> std::io::Result<()> {
+    let limits = [
+        (libc::RLIMIT_AS, MAX_ADDRESS_SPACE_BYTES),
+        (libc::RLIMIT_CPU, cpu_seconds),
+        // Output only goes to the pipe, never to files
+        (libc::RLIMIT_FSIZE, 0),
+    ];
+    for (resource, limit) in limits {
+        let rlimit = libc::rlimit {
+            rlim_cur: limit as libc::rlim_t,
+            rlim_max: limit as libc::rlim_t,
+        };
+        // SAFETY: rlimit is a valid, initialized struct
+        if unsafe { libc::setrlimit(resource, &rlimit) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
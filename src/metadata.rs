@@ -0,0 +1,471 @@
+use anyhow::{Result, anyhow};
+
+use crate::animation::{riff_chunks, write_chunk};
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const XMP_CONTENT_TYPE: &str = "application/rdf+xml";
+// EXIF tag holding the orientation in IFD0
+const ORIENTATION_TAG: u16 = 0x0112;
+// VP8X flags announcing EXIF and XMP chunks
+const WEBP_EXIF_FLAG: u8 = 0x08;
+const WEBP_XMP_FLAG: u8 = 0x04;
+// ISO base media brands of HEIF-based formats, including AVIF
+const HEIF_BRANDS: &[&[u8; 4]] = &[
+    b"heic", b"heix", b"heim", b"heis", b"hevc", b"hevx", b"mif1", b"msf1", b"avif", b"avis",
+];
+
+/// Removes EXIF, XMP, IPTC and comment metadata from JPEG, PNG, WebP and
+/// HEIF files without re-encoding them. The EXIF orientation is kept so the
+/// image is still displayed upright. Returns None if the format is not
+/// supported or there was nothing to remove.
+pub fn strip(data: &[u8]) -> Result<Option<Vec<u8>>> {
+    let stripped = if data.starts_with(&[0xff, 0xd8]) {
+        strip_jpeg(data)?
+    } else if data.starts_with(PNG_SIGNATURE) {
+        strip_png(data)?
+    } else if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        strip_webp(data)?
+    } else if is_heif(data) {
+        strip_heif(data)?
+    } else {
+        return Ok(None);
+    };
+    Ok((stripped != data).then_some(stripped))
+}
+
+/// Reads the orientation from EXIF data starting at the TIFF header.
+fn exif_orientation(tiff: &[u8]) -> Option<u16> {
+    let big_endian = match tiff.get(..4)? {
+        b"MM\0*" => true,
+        b"II*\0" => false,
+        _ => return None,
+    };
+    let u16_at = |offset: usize| {
+        let bytes = [*tiff.get(offset)?, *tiff.get(offset + 1)?];
+        Some(if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    };
+    let u32_at = |offset: usize| {
+        let bytes: [u8; 4] = tiff.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    };
+    let ifd = u32_at(4)? as usize;
+    let count = u16_at(ifd)? as usize;
+    (0..count)
+        .map(|index| ifd + 2 + index * 12)
+        .find(|entry| u16_at(*entry) == Some(ORIENTATION_TAG))
+        // SHORT values are stored at the start of the value field
+        .and_then(|entry| u16_at(entry + 8))
+        .filter(|orientation| (2..=8).contains(orientation))
+}
+
+/// Builds EXIF data, starting at the TIFF header, holding nothing but the
+/// orientation.
+fn orientation_exif(orientation: u16) -> Vec<u8> {
+    let mut tiff = Vec::with_capacity(26);
+    tiff.extend_from_slice(b"MM\0*");
+    tiff.extend_from_slice(&8u32.to_be_bytes());
+    tiff.extend_from_slice(&1u16.to_be_bytes());
+    tiff.extend_from_slice(&ORIENTATION_TAG.to_be_bytes());
+    // One SHORT
+    tiff.extend_from_slice(&3u16.to_be_bytes());
+    tiff.extend_from_slice(&1u32.to_be_bytes());
+    tiff.extend_from_slice(&orientation.to_be_bytes());
+    tiff.extend_from_slice(&[0, 0]);
+    // No further IFDs
+    tiff.extend_from_slice(&0u32.to_be_bytes());
+    tiff
+}
+
+/// Keeps the image data, JFIF, ICC profile and Adobe segments. Anything
+/// after the end of the image, such as the extra pictures of MPF files, is
+/// dropped along with its metadata.
+fn strip_jpeg(data: &[u8]) -> Result<Vec<u8>> {
+    let mut output = Vec::with_capacity(data.len());
+    output.extend_from_slice(&data[..2]);
+    let mut orientation = None;
+    let mut offset = 2;
+    loop {
+        if data.get(offset) != Some(&0xff) {
+            return Err(anyhow!("Invalid JPEG marker at offset {}", offset));
+        }
+        // Markers may be preceded by fill bytes
+        while data.get(offset + 1) == Some(&0xff) {
+            offset += 1;
+        }
+        let marker = *data
+            .get(offset + 1)
+            .ok_or_else(|| anyhow!("Truncated JPEG"))?;
+        if marker == 0xd9 {
+            output.extend_from_slice(&[0xff, 0xd9]);
+            break;
+        }
+        if marker == 0x01 || (0xd0..=0xd7).contains(&marker) {
+            output.extend_from_slice(&[0xff, marker]);
+            offset += 2;
+            continue;
+        }
+        let length = data
+            .get(offset + 2..offset + 4)
+            .map(|length| u16::from_be_bytes([length[0], length[1]]) as usize)
+            .filter(|length| *length >= 2)
+            .ok_or_else(|| anyhow!("Truncated JPEG"))?;
+        let end = offset + 2 + length;
+        let segment = data
+            .get(offset..end)
+            .ok_or_else(|| anyhow!("Truncated JPEG"))?;
+        let payload = &segment[4..];
+        match marker {
+            // APP1 holds EXIF and XMP
+            0xe1 => {
+                if let Some(tiff) = payload.strip_prefix(EXIF_HEADER)
+                    && orientation.is_none()
+                {
+                    orientation = exif_orientation(tiff);
+                    if let Some(orientation) = orientation {
+                        let mut exif = EXIF_HEADER.to_vec();
+                        exif.extend_from_slice(&orientation_exif(orientation));
+                        output.extend_from_slice(&[0xff, 0xe1]);
+                        output.extend_from_slice(&(exif.len() as u16 + 2).to_be_bytes());
+                        output.extend_from_slice(&exif);
+                    }
+                }
+            }
+            // APP2 is kept for ICC profiles, but not for MPF indexes that
+            // point past the end of the image
+            0xe2 if payload.starts_with(b"MPF\0") => {}
+            // APP13 holds IPTC, COM holds comments
+            0xed | 0xfe => {}
+            // SOS is followed by entropy-coded data, which ends at the first
+            // marker other than a stuffed byte or a restart marker
+            0xda => {
+                let mut scan_end = end;
+                while let Some(byte) = data.get(scan_end) {
+                    if *byte == 0xff
+                        && let Some(next) = data.get(scan_end + 1)
+                        && *next != 0
+                        && *next != 0xff
+                        && !(0xd0..=0xd7).contains(next)
+                    {
+                        break;
+                    }
+                    scan_end += 1;
+                }
+                output.extend_from_slice(&data[offset..scan_end]);
+                offset = scan_end;
+                continue;
+            }
+            _ => output.extend_from_slice(segment),
+        }
+        offset = end;
+    }
+    Ok(output)
+}
+
+/// Drops text chunks, which hold XMP and comments, and anything after IEND.
+fn strip_png(data: &[u8]) -> Result<Vec<u8>> {
+    let mut output = Vec::with_capacity(data.len());
+    output.extend_from_slice(PNG_SIGNATURE);
+    let mut offset = PNG_SIGNATURE.len();
+    loop {
+        let header = data
+            .get(offset..offset + 8)
+            .ok_or_else(|| anyhow!("Truncated PNG"))?;
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let chunk_type = &header[4..8];
+        // Chunk length, type, data and CRC
+        let end = offset + 12 + length;
+        let chunk = data
+            .get(offset..end)
+            .ok_or_else(|| anyhow!("Truncated PNG"))?;
+        match chunk_type {
+            b"eXIf" => {
+                if let Some(orientation) = exif_orientation(&chunk[8..8 + length]) {
+                    let exif = orientation_exif(orientation);
+                    let mut typed = b"eXIf".to_vec();
+                    typed.extend_from_slice(&exif);
+                    output.extend_from_slice(&(exif.len() as u32).to_be_bytes());
+                    output.extend_from_slice(&typed);
+                    output.extend_from_slice(&crc32fast::hash(&typed).to_be_bytes());
+                }
+            }
+            b"tEXt" | b"zTXt" | b"iTXt" => {}
+            _ => output.extend_from_slice(chunk),
+        }
+        if chunk_type == b"IEND" {
+            break;
+        }
+        offset = end;
+    }
+    Ok(output)
+}
+
+/// Drops the XMP chunk and reduces the EXIF chunk to the orientation,
+/// updating the VP8X flags to match.
+fn strip_webp(data: &[u8]) -> Result<Vec<u8>> {
+    let mut chunks = Vec::with_capacity(data.len());
+    let mut has_exif = false;
+    let mut end = 12;
+    for (fourcc, payload) in riff_chunks(data) {
+        end += 8 + payload.len() + (payload.len() & 1);
+        match &fourcc {
+            b"EXIF" => {
+                // Some encoders include the JPEG APP1 prefix
+                let tiff = payload.strip_prefix(EXIF_HEADER).unwrap_or(payload);
+                if let Some(orientation) = exif_orientation(tiff) {
+                    write_chunk(&mut chunks, b"EXIF", &orientation_exif(orientation));
+                    has_exif = true;
+                }
+            }
+            b"XMP " => {}
+            _ => write_chunk(&mut chunks, &fourcc, payload),
+        }
+    }
+    // riff_chunks stops at a chunk running past the end of the data, which
+    // would otherwise be dropped along with everything after it
+    let riff_size = data
+        .get(4..8)
+        .map(|size| u32::from_le_bytes([size[0], size[1], size[2], size[3]]) as usize)
+        .ok_or_else(|| anyhow!("Truncated WebP"))?;
+    if end < data.len().min(riff_size.saturating_add(8)) {
+        return Err(anyhow!("Truncated WebP chunk at offset {}", end));
+    }
+    // The VP8X chunk, if any, is always first
+    if chunks.starts_with(b"VP8X") && chunks.len() > 8 {
+        chunks[8] &= !(WEBP_EXIF_FLAG | WEBP_XMP_FLAG);
+        if has_exif {
+            chunks[8] |= WEBP_EXIF_FLAG;
+        }
+    }
+
+    let mut output = Vec::with_capacity(chunks.len() + 12);
+    output.extend_from_slice(b"RIFF");
+    output.extend_from_slice(&(chunks.len() as u32 + 4).to_le_bytes());
+    output.extend_from_slice(b"WEBP");
+    output.extend_from_slice(&chunks);
+    Ok(output)
+}
+
+fn is_heif(data: &[u8]) -> bool {
+    let Some((b"ftyp", start, end)) = iso_boxes(data, 0, data.len()).next() else {
+        return false;
+    };
+    // Major brand, minor version, then compatible brands
+    data[start..end]
+        .chunks_exact(4)
+        .enumerate()
+        .filter(|(index, _)| *index != 1)
+        .any(|(_, brand)| HEIF_BRANDS.iter().any(|heif| brand == *heif))
+}
+
+/// Iterates over the boxes of an ISO base media file between `start` and
+/// `end`, yielding each box type with the range of its payload.
+fn iso_boxes(
+    data: &[u8],
+    start: usize,
+    end: usize,
+) -> impl Iterator<Item = (&[u8; 4], usize, usize)> {
+    let mut offset = start;
+    std::iter::from_fn(move || {
+        let header = data.get(offset..(offset + 8).min(end))?;
+        let box_type: &[u8; 4] = header.get(4..8)?.try_into().ok()?;
+        let (header_size, size) =
+            match u32::from_be_bytes([header[0], header[1], header[2], header[3]]) {
+                // The box extends to the end of its parent
+                0 => (8, end - offset),
+                // A 64-bit size follows the type
+                1 => {
+                    let size = data.get(offset + 8..offset + 16)?;
+                    (16, u64::from_be_bytes(size.try_into().ok()?) as usize)
+                }
+                size => (8, size as usize),
+            };
+        let box_end = offset.checked_add(size).filter(|box_end| *box_end <= end)?;
+        if size < header_size {
+            return None;
+        }
+        let payload_start = offset + header_size;
+        offset = box_end;
+        Some((box_type, payload_start, box_end))
+    })
+}
+
+/// HEIF keeps orientation in its own item properties, so Exif items are
+/// blanked entirely. Their data is overwritten in place, which leaves every
+/// offset in the file valid.
+fn strip_heif(data: &[u8]) -> Result<Vec<u8>> {
+    let Some((_, meta_start, meta_end)) =
+        iso_boxes(data, 0, data.len()).find(|(box_type, _, _)| *box_type == b"meta")
+    else {
+        return Ok(data.to_vec());
+    };
+    // meta is a full box: skip its version and flags
+    let children: Vec<_> = iso_boxes(data, meta_start + 4, meta_end).collect();
+    let find = |wanted: &[u8; 4]| {
+        children
+            .iter()
+            .find(|(box_type, _, _)| *box_type == wanted)
+            .map(|(_, start, end)| &data[*start..*end])
+    };
+    let (Some(iinf), Some(iloc)) = (find(b"iinf"), find(b"iloc")) else {
+        return Ok(data.to_vec());
+    };
+    let idat_start = children
+        .iter()
+        .find(|(box_type, _, _)| *box_type == b"idat")
+        .map(|(_, start, _)| *start);
+
+    let mut output = data.to_vec();
+    for (item_id, is_exif) in metadata_items(iinf)? {
+        for (construction_method, offset, length) in item_extents(iloc, item_id)? {
+            let base = match construction_method {
+                0 => 0,
+                1 => idat_start.ok_or_else(|| anyhow!("HEIF item refers to missing idat"))?,
+                // Items built from other items hold no data of their own
+                _ => continue,
+            };
+            let start = base
+                .checked_add(offset)
+                .filter(|start| {
+                    start
+                        .checked_add(length)
+                        .is_some_and(|end| end <= data.len())
+                })
+                .ok_or_else(|| anyhow!("HEIF item extent is out of bounds"))?;
+            let extent = &mut output[start..start + length];
+            if is_exif {
+                blank_exif(extent);
+            } else {
+                extent.fill(b' ');
+            }
+        }
+    }
+    Ok(output)
+}
+
+/// Replaces an Exif item with an empty TIFF directory, zero padded.
+fn blank_exif(extent: &mut [u8]) {
+    // Offset to the TIFF header, then a header pointing at an IFD with no
+    // entries and no successor
+    const EMPTY: &[u8] = b"\0\0\0\0MM\0*\0\0\0\x08\0\0\0\0\0\0";
+    extent.fill(0);
+    if extent.len() >= EMPTY.len() {
+        extent[..EMPTY.len()].copy_from_slice(EMPTY);
+    }
+}
+
+/// Returns the IDs of Exif and XMP items, with true for Exif items.
+fn metadata_items(iinf: &[u8]) -> Result<Vec<(u32, bool)>> {
+    let version = *iinf.first().ok_or_else(|| anyhow!("Truncated iinf box"))?;
+    let entries_start = if version == 0 { 6 } else { 8 };
+    let mut items = Vec::new();
+    for (box_type, start, end) in iso_boxes(iinf, entries_start, iinf.len()) {
+        if box_type != b"infe" {
+            continue;
+        }
+        let infe = &iinf[start..end];
+        // Versions before 2 don't have item types
+        let (item_id, rest) = match infe.first() {
+            Some(2) => (
+                infe.get(4..6)
+                    .map(|id| u16::from_be_bytes([id[0], id[1]]) as u32),
+                infe.get(8..),
+            ),
+            Some(3) => (
+                infe.get(4..8)
+                    .map(|id| u32::from_be_bytes([id[0], id[1], id[2], id[3]])),
+                infe.get(10..),
+            ),
+            _ => continue,
+        };
+        let (Some(item_id), Some(rest)) = (item_id, rest) else {
+            return Err(anyhow!("Truncated infe box"));
+        };
+        let item_type = rest.get(..4);
+        if item_type == Some(b"Exif") {
+            items.push((item_id, true));
+        } else if item_type == Some(b"mime") {
+            // Item name, then content type, both null-terminated
+            let mut strings = rest[4..].split(|byte| *byte == 0).skip(1);
+            if strings.next() == Some(XMP_CONTENT_TYPE.as_bytes()) {
+                items.push((item_id, false));
+            }
+        }
+    }
+    Ok(items)
+}
+
+/// Returns the construction method, offset and length of each extent of an
+/// item in the iloc box.
+fn item_extents(iloc: &[u8], wanted: u32) -> Result<Vec<(u8, usize, usize)>> {
+    let mut reader = BoxReader {
+        data: iloc,
+        offset: 0,
+    };
+    let version = reader.read(1)?;
+    reader.read(3)?;
+    let sizes = reader.read(2)?;
+    let offset_size = (sizes >> 12) as usize;
+    let length_size = ((sizes >> 8) & 0xf) as usize;
+    let base_offset_size = ((sizes >> 4) & 0xf) as usize;
+    let index_size = if version > 0 {
+        (sizes & 0xf) as usize
+    } else {
+        0
+    };
+    let item_count = reader.read(if version < 2 { 2 } else { 4 })?;
+    for _ in 0..item_count {
+        let item_id = reader.read(if version < 2 { 2 } else { 4 })? as u32;
+        let construction_method = if version > 0 {
+            (reader.read(2)? & 0xf) as u8
+        } else {
+            0
+        };
+        // Data reference index
+        reader.read(2)?;
+        let base_offset = reader.read(base_offset_size)? as usize;
+        let extent_count = reader.read(2)?;
+        let mut extents = Vec::new();
+        for _ in 0..extent_count {
+            reader.read(index_size)?;
+            let offset = reader.read(offset_size)? as usize;
+            let length = reader.read(length_size)? as usize;
+            let offset = base_offset
+                .checked_add(offset)
+                .ok_or_else(|| anyhow!("HEIF item extent offset overflows"))?;
+            extents.push((construction_method, offset, length));
+        }
+        if item_id == wanted {
+            return Ok(extents);
+        }
+    }
+    Ok(Vec::new())
+}
+
+struct BoxReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl BoxReader<'_> {
+    /// Reads a big-endian integer of up to 8 bytes.
+    fn read(&mut self, size: usize) -> Result<u64> {
+        let bytes = self
+            .data
+            .get(self.offset..self.offset + size)
+            .filter(|_| size <= 8)
+            .ok_or_else(|| anyhow!("Truncated iloc box"))?;
+        self.offset += size;
+        Ok(bytes
+            .iter()
+            .fold(0, |value, byte| (value << 8) | *byte as u64))
+    }
+}
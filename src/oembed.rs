@@ -0,0 +1,239 @@
+use anyhow::{Context, Result, anyhow};
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use scraper::{Html, Selector};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use url::Url;
+
+use crate::{environment::OEMBED_PROVIDERS_PATH, fetch};
+
+const BUILTIN_PROVIDERS: &str = include_str!("oembed-providers.json");
+const OEMBED_MAX_BYTES: usize = 256 * 1024;
+const OEMBED_CONTENT_TYPES: &[&str] = &["application/json", "text/"];
+
+lazy_static! {
+    static ref PROVIDERS: Vec<Provider> = load_providers();
+}
+
+// Same format as https://oembed.com/providers.json
+#[derive(Debug, Deserialize)]
+struct Provider {
+    provider_name: String,
+    endpoints: Vec<Endpoint>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Endpoint {
+    #[serde(default)]
+    schemes: Vec<String>,
+    url: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Embed {
+    // One of "photo", "video", "link" or "rich"
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub url: Option<String>,
+    // The provider markup reduced to a bare <iframe> of `iframe_url`, since
+    // anything else in it would be passed to clients unchecked
+    pub html: Option<String>,
+    // Source of the <iframe> in `html`, for clients that do not render HTML
+    pub iframe_url: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub title: Option<String>,
+    pub author_name: Option<String>,
+    pub author_url: Option<String>,
+    pub provider_name: Option<String>,
+    pub provider_url: Option<String>,
+    pub thumbnail_url: Option<String>,
+}
+
+fn load_providers() -> Vec<Provider> {
+    let json = match OEMBED_PROVIDERS_PATH.as_deref() {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(json) => json,
+            Err(e) => {
+                warn!("Failed to read oEmbed providers from {}: {}", path, e);
+                BUILTIN_PROVIDERS.to_string()
+            }
+        },
+        None => BUILTIN_PROVIDERS.to_string(),
+    };
+    match serde_json::from_str::<Vec<Provider>>(&json) {
+        Ok(providers) => {
+            info!("Loaded {} oEmbed providers", providers.len());
+            providers
+        }
+        Err(e) => {
+            warn!("Failed to parse oEmbed providers: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Matches `value` against a glob where `*` stands for any text.
+fn glob_matches(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = value.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No wildcard at all
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+// Splits "https://host/path" into "https://host" and "/path"
+fn split_origin(url: &str) -> (&str, &str) {
+    let authority_start = url.find("://").map(|index| index + 3).unwrap_or(0);
+    match url[authority_start..].find(['/', '?']) {
+        Some(index) => url.split_at(authority_start + index),
+        None => (url, ""),
+    }
+}
+
+/// Matches a URL against a provider scheme. Wildcards in the host only match
+/// within the host, so that a scheme cannot be satisfied by another site.
+fn scheme_matches(scheme: &str, url: &str) -> bool {
+    let (scheme_origin, scheme_path) = split_origin(scheme);
+    let (url_origin, url_path) = split_origin(url);
+    glob_matches(scheme_origin, url_origin) && glob_matches(scheme_path, url_path)
+}
+
+/// Returns the oEmbed URL for a page from the provider registry, if any
+/// provider claims it.
+pub fn find_provider_endpoint(url: &str) -> Option<String> {
+    for provider in PROVIDERS.iter() {
+        for endpoint in &provider.endpoints {
+            if endpoint
+                .schemes
+                .iter()
+                .any(|scheme| scheme_matches(scheme, url))
+            {
+                debug!(
+                    "Using oEmbed provider {} for {}",
+                    provider.provider_name, url
+                );
+                let mut endpoint_url =
+                    Url::parse(&endpoint.url.replace("{format}", "json")).ok()?;
+                endpoint_url
+                    .query_pairs_mut()
+                    .append_pair("url", url)
+                    .append_pair("format", "json");
+                return Some(endpoint_url.to_string());
+            }
+        }
+    }
+    None
+}
+
+/// Returns the oEmbed URL advertised by the page through
+/// `<link rel="alternate" type="application/json+oembed">`.
+pub fn discover_endpoint(document: &Html, base: &str) -> Option<String> {
+    let selector = Selector::parse(r#"link[type="application/json+oembed"]"#).ok()?;
+    let href = document
+        .select(&selector)
+        .find(|element| {
+            element
+                .value()
+                .attr("rel")
+                .is_some_and(|rel| rel.split_whitespace().any(|rel| rel == "alternate"))
+        })?
+        .value()
+        .attr("href")?;
+    Url::parse(base).ok()?.join(href).ok().map(String::from)
+}
+
+fn string_field(value: &Value, key: &str) -> Option<String> {
+    value
+        .get(key)
+        .and_then(Value::as_str)
+        .filter(|s| !s.trim().is_empty())
+        .map(str::to_string)
+}
+
+// Providers send dimensions as numbers or strings
+fn dimension_field(value: &Value, key: &str) -> Option<u32> {
+    match value.get(key)? {
+        Value::Number(number) => number.as_f64().map(|n| n as u32),
+        Value::String(string) => string.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+/// Returns the source of the first <iframe> in the provider markup, resolved
+/// against the endpoint so protocol-relative sources work. Only https
+/// sources are kept.
+fn extract_iframe_url(html: &str, endpoint: &str) -> Option<String> {
+    let fragment = Html::parse_fragment(html);
+    let selector = Selector::parse("iframe").ok()?;
+    let src = fragment.select(&selector).next()?.value().attr("src")?;
+    let url = Url::parse(endpoint).ok()?.join(src.trim()).ok()?;
+    (url.scheme() == "https").then(|| url.to_string())
+}
+
+fn escape_attribute(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn iframe_html(src: &str, width: Option<u32>, height: Option<u32>) -> String {
+    let mut html = format!(r#"<iframe src="{}""#, escape_attribute(src));
+    if let Some(width) = width {
+        html.push_str(&format!(r#" width="{}""#, width));
+    }
+    if let Some(height) = height {
+        html.push_str(&format!(r#" height="{}""#, height));
+    }
+    html.push_str(r#" frameborder="0" allowfullscreen></iframe>"#);
+    html
+}
+
+pub async fn fetch_embed(endpoint: &str) -> Result<Embed> {
+    let response = fetch::get(endpoint)
+        .await
+        .context("Failed to fetch oEmbed")?;
+    if !response.status().is_success() {
+        return Err(anyhow!("oEmbed endpoint returned {}", response.status()));
+    }
+    fetch::check_content_type(&response, OEMBED_CONTENT_TYPES)?;
+    let body = fetch::read_body(response, OEMBED_MAX_BYTES).await?;
+    let value: Value = serde_json::from_slice(&body).context("Failed to parse oEmbed")?;
+
+    let kind = string_field(&value, "type").ok_or_else(|| anyhow!("oEmbed type missing"))?;
+    let iframe_url =
+        string_field(&value, "html").and_then(|html| extract_iframe_url(&html, endpoint));
+    let width = dimension_field(&value, "width");
+    let height = dimension_field(&value, "height");
+    Ok(Embed {
+        kind,
+        url: string_field(&value, "url"),
+        html: iframe_url
+            .as_deref()
+            .map(|src| iframe_html(src, width, height)),
+        iframe_url,
+        width,
+        height,
+        title: string_field(&value, "title"),
+        author_name: string_field(&value, "author_name"),
+        author_url: string_field(&value, "author_url"),
+        provider_name: string_field(&value, "provider_name"),
+        provider_url: string_field(&value, "provider_url"),
+        thumbnail_url: string_field(&value, "thumbnail_url"),
+    })
+}
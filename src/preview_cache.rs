@@ -0,0 +1,138 @@
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use lru::LruCache;
+use mongodb::bson::DateTime;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::{
+    database::{DerivativeRepository, PreviewCacheDocument, PreviewCacheRepository},
+    environment::{
+        PREVIEW_CACHE_CAPACITY, PREVIEW_CACHE_DEFAULT_SECONDS, PREVIEW_CACHE_MAX_SECONDS,
+        PREVIEW_CACHE_MIN_SECONDS, PREVIEW_CACHE_NEGATIVE_SECONDS,
+    },
+    fetch, get_time_millis,
+    routes::preview::{LinkPreview, fetch_preview},
+};
+
+// Query parameters that only track where a link was shared
+const TRACKING_PARAMS: &[&str] = &["fbclid", "gclid", "igshid", "mc_eid", "ref_src"];
+
+lazy_static! {
+    static ref MEMORY_CACHE: Mutex<LruCache<String, (CachedPreview, u64)>> = Mutex::new(
+        LruCache::new(NonZeroUsize::new(*PREVIEW_CACHE_CAPACITY).unwrap_or(NonZeroUsize::MIN))
+    );
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum CachedPreview {
+    Found { preview: Box<LinkPreview> },
+    // The URL was refused by the fetch policy
+    Rejected { error: String },
+    Failed { error: String },
+}
+
+/// Normalizes a URL for use as a cache key: drops the fragment and tracking
+/// parameters, and lets the URL parser canonicalize scheme, host and port.
+pub fn normalize_url(url: &str) -> Option<String> {
+    let mut url = Url::parse(url).ok()?;
+    url.set_fragment(None);
+    let params: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(key, _)| !key.starts_with("utm_") && !TRACKING_PARAMS.contains(&key.as_ref()))
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+    if params.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(params);
+    }
+    Some(url.to_string())
+}
+
+/// Returns the preview for the URL from the in-memory or MongoDB cache,
+/// fetching and caching it on a miss. Failures are cached too, for
+/// `PREVIEW_CACHE_NEGATIVE_SECONDS`.
+pub async fn get_preview(url: &str) -> CachedPreview {
+    let Some(url) = normalize_url(url) else {
+        return CachedPreview::Rejected {
+            error: fetch::FetchError::InvalidUrl.to_string(),
+        };
+    };
+
+    if let Some((entry, expires_at)) = MEMORY_CACHE.lock().unwrap().get(&url)
+        && *expires_at > get_time_millis()
+    {
+        debug!("Preview cache hit (memory): {}", url);
+        return entry.clone();
+    }
+
+    match PreviewCacheRepository::get_entry(&url).await {
+        Ok(Some(document)) => {
+            debug!("Preview cache hit (database): {}", url);
+            // Keeps the rehosted image from being collected while the entry is
+            // served. Memory hits need no touch since memory entries only
+            // live as long as the entry they were loaded or fetched with.
+            if let CachedPreview::Found { preview } = &document.entry
+                && let Some(hash) = &preview.image_hash
+                && let Err(e) = DerivativeRepository::touch(hash).await
+            {
+                warn!("Failed to record use of derivative {}: {}", hash, e);
+            }
+            MEMORY_CACHE.lock().unwrap().put(
+                url,
+                (
+                    document.entry.clone(),
+                    document.expires_at.timestamp_millis() as u64,
+                ),
+            );
+            return document.entry;
+        }
+        Ok(None) => {}
+        Err(e) => warn!("Failed to read preview cache for {}: {}", url, e),
+    }
+
+    let (entry, ttl) = match fetch_preview(&url).await {
+        Ok((preview, max_age)) => (
+            CachedPreview::Found {
+                preview: Box::new(preview),
+            },
+            max_age
+                .unwrap_or(*PREVIEW_CACHE_DEFAULT_SECONDS)
+                .clamp(*PREVIEW_CACHE_MIN_SECONDS, *PREVIEW_CACHE_MAX_SECONDS),
+        ),
+        Err(e) => match fetch::rejection(&e) {
+            Some(rejection) => (
+                CachedPreview::Rejected {
+                    error: rejection.to_string(),
+                },
+                *PREVIEW_CACHE_NEGATIVE_SECONDS,
+            ),
+            None => (
+                CachedPreview::Failed {
+                    error: format!("{:#}", e),
+                },
+                *PREVIEW_CACHE_NEGATIVE_SECONDS,
+            ),
+        },
+    };
+
+    let expires_at = get_time_millis() + ttl * 1000;
+    MEMORY_CACHE
+        .lock()
+        .unwrap()
+        .put(url.clone(), (entry.clone(), expires_at));
+    let document = PreviewCacheDocument {
+        url: url.clone(),
+        entry: entry.clone(),
+        expires_at: DateTime::from_millis(expires_at as i64),
+    };
+    if let Err(e) = PreviewCacheRepository::save_entry(document).await {
+        warn!("Failed to save preview cache for {}: {}", url, e);
+    }
+    entry
+}
@@ -0,0 +1,117 @@
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, Result as ActixResult, web};
+use log::{error, info};
+use serde::Serialize;
+
+use crate::{
+    ErrorResponse,
+    database::{AudioMetadata, FileDocument, FileRepository, ImageMetadata, MediaMetadata},
+    derivative,
+    routes::preview_image::TransformOptions,
+    signature,
+};
+
+#[derive(Serialize)]
+pub struct FileUrlResponse {
+    url: String,
+}
+
+#[derive(Serialize)]
+pub struct FileMetadataResponse {
+    id: String,
+    name: Option<String>,
+    content_type: String,
+    size: u64,
+    uploaded_at: i64,
+    linked: bool,
+    image: Option<ImageMetadata>,
+    media: Option<MediaMetadata>,
+    // Signed URL of the video's poster frame
+    poster_url: Option<String>,
+    audio: Option<AudioMetadata>,
+}
+
+fn user_id(req: &HttpRequest) -> ActixResult<String> {
+    req.extensions()
+        .get::<String>()
+        .cloned()
+        .ok_or(actix_web::error::ErrorUnauthorized(
+            "User ID not found in request",
+        ))
+}
+
+/// Looks up a file owned by the user, or the response to send if there is
+/// none. Files of other users are reported as missing.
+async fn find_owned_file(file_id: &str, user_id: &str) -> Result<FileDocument, HttpResponse> {
+    match FileRepository::get_file(file_id).await {
+        Ok(Some(doc)) if doc.user_id == user_id => Ok(doc),
+        Ok(_) => Err(HttpResponse::NotFound().json(ErrorResponse {
+            error: "File not found".to_string(),
+        })),
+        Err(e) => {
+            error!("MongoDB error: {}", e);
+            Err(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Database error".to_string(),
+            }))
+        }
+    }
+}
+
+/// Returns the metadata of one of the user's files, including the
+/// dimensions of images so clients can reserve space before loading them.
+pub async fn get_file(req: HttpRequest, path: web::Path<String>) -> ActixResult<HttpResponse> {
+    let user_id = user_id(&req)?;
+    let file_id = path.into_inner();
+    info!("Getting metadata for file: {}", file_id);
+    let file_doc = match find_owned_file(&file_id, &user_id).await {
+        Ok(doc) => doc,
+        Err(response) => return Ok(response),
+    };
+    Ok(HttpResponse::Ok().json(FileMetadataResponse {
+        id: file_doc.id,
+        name: file_doc.name,
+        content_type: file_doc.content_type,
+        size: file_doc.size,
+        uploaded_at: file_doc.uploaded_at.timestamp_millis(),
+        linked: file_doc.linked,
+        image: file_doc.image,
+        poster_url: file_doc
+            .media
+            .as_ref()
+            .and_then(|media| media.poster_hash.as_deref())
+            .and_then(derivative::signed_url),
+        media: file_doc.media,
+        audio: file_doc.audio,
+    }))
+}
+
+/// Signs a serve URL for one of the user's files, including any transform
+/// parameters, e.g. `/api/files/{id}/url?width=256&format=webp`.
+pub async fn get_file_url(
+    req: HttpRequest,
+    path: web::Path<String>,
+    transform: web::Query<TransformOptions>,
+) -> ActixResult<HttpResponse> {
+    let user_id = user_id(&req)?;
+    let file_id = path.into_inner();
+    info!("Signing URL for file: {}", file_id);
+    if let Err(error) = transform.validate() {
+        return Ok(HttpResponse::BadRequest().json(ErrorResponse { error }));
+    }
+    let file_doc = match find_owned_file(&file_id, &user_id).await {
+        Ok(doc) => doc,
+        Err(response) => return Ok(response),
+    };
+
+    let params = transform.normalized();
+    let (signature, timestamp) = signature::generate_signature(
+        &signature::signed_resource(&file_id, &params),
+        &file_doc.signing_key,
+    );
+    let mut url = format!("/files/{}?", file_id);
+    if !params.is_empty() {
+        url.push_str(&params);
+        url.push('&');
+    }
+    url.push_str(&format!("signature={}&timestamp={}", signature, timestamp));
+    Ok(HttpResponse::Ok().json(FileUrlResponse { url }))
+}
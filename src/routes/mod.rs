@@ -1,4 +1,6 @@
+pub mod files;
 pub mod preview;
 pub mod preview_image;
+pub mod proxy;
 pub mod serve;
 pub mod upload;
@@ -1,61 +1,402 @@
 use actix_web::{HttpResponse, Result as ActixResult, web};
-use anyhow::{Context, Result};
-use lazy_static::lazy_static;
-use log::{error, info};
-use reqwest;
+use anyhow::Result;
+use futures_util::future::join_all;
+use image::ImageReader;
+use log::{error, info, warn};
 use scraper::{Html, Selector};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::{
+    sync::Semaphore,
+    time::{Instant, timeout_at},
+};
 
-use crate::ErrorResponse;
+use crate::{
+    ErrorResponse, derivative,
+    environment::{
+        FETCH_MAX_HTML_BYTES, PREVIEW_BATCH_HOST_CONCURRENCY, PREVIEW_BATCH_MAX_URLS,
+        PREVIEW_BATCH_TIMEOUT_SECONDS, PREVIEW_REHOST_IMAGES, PREVIEW_REHOST_MAX_WIDTH,
+    },
+    fetch::{self, ReadProgress},
+    oembed::{self, Embed},
+    preview_cache::{self, CachedPreview},
+    routes::preview_image::{self, OutputOptions, TransformOptions},
+    text,
+};
 
-lazy_static! {
-    pub static ref HTTP_CLIENT: reqwest::Client = {
-        reqwest::Client::builder()
-            .user_agent("Mozilla/5.0 (Linux x86_64; rv:140.0) Gecko/20100101 Firefox/140.0")
-            .timeout(std::time::Duration::from_secs(10))
-            .build()
-            .expect("Failed to create HTTP client")
-    };
+const HTML_CONTENT_TYPES: &[&str] = &["text/html", "application/xhtml+xml"];
+const HEAD_END: &[u8] = b"</head>";
+// Meta tags whose presence in <head> means the body is not needed
+const TITLE_METAS: &[&[u8]] = &[b"og:title", b"twitter:title"];
+// Enough for the headers of common image formats, including JPEG with EXIF
+const IMAGE_HEADER_BYTES: usize = 64 * 1024;
+const MAX_TITLE_CHARS: usize = 300;
+const MAX_DESCRIPTION_CHARS: usize = 1000;
+const MAX_SHORT_TEXT_CHARS: usize = 200;
+const MAX_URL_LENGTH: usize = 2048;
+const MAX_EMBED_HTML_LENGTH: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PreviewKind {
+    #[default]
+    Page,
+    Image,
+    Video,
+    Audio,
+    File,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LinkPreview {
     pub url: String,
+    #[serde(default)]
+    pub kind: PreviewKind,
+    // Only set for direct media links
+    pub mime_type: Option<String>,
+    pub size: Option<u64>,
+    pub canonical_url: Option<String>,
     pub title: Option<String>,
     pub description: Option<String>,
     pub image: Option<String>,
+    // Set when the image was copied into the CDN, in which case `image` is
+    // replaced with a freshly signed URL on every response
+    #[serde(default)]
+    pub image_hash: Option<String>,
+    pub image_width: Option<u32>,
+    pub image_height: Option<u32>,
+    // Placeholders for rehosted images, ThumbHash base64 encoded
+    #[serde(default)]
+    pub image_blurhash: Option<String>,
+    #[serde(default)]
+    pub image_thumbhash: Option<String>,
+    pub image_alt: Option<String>,
+    pub video: Option<PreviewMedia>,
+    pub audio: Option<PreviewMedia>,
     pub site_name: Option<String>,
+    pub favicon: Option<String>,
+    pub theme_color: Option<String>,
+    pub published_time: Option<String>,
+    pub author: Option<String>,
+    pub embed: Option<Embed>,
 }
 
-pub async fn fetch_preview(url: &str) -> Result<LinkPreview> {
-    let response = HTTP_CLIENT
-        .get(url)
-        .send()
-        .await
-        .context("Failed to fetch URL")?;
-    let html = response
-        .text()
-        .await
-        .context("Failed to read response body")?;
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PreviewMedia {
+    pub url: String,
+    pub mime_type: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+/// Fetches and parses the page. Also returns the upstream cache lifetime in
+/// seconds, if the response specified one.
+pub async fn fetch_preview(url: &str) -> Result<(LinkPreview, Option<u64>)> {
+    let response = fetch::get(url).await?;
+    let max_age = fetch::cache_max_age(&response);
+    if let Some(content_type) = fetch::content_type(&response)
+        && !fetch::content_type_matches(&content_type, HTML_CONTENT_TYPES)
+    {
+        let preview = media_preview(response, url, content_type).await?;
+        return Ok((finish_preview(preview).await, max_age));
+    }
+    let charset = fetch::content_type_charset(&response);
+
+    // Pages with Open Graph or Twitter metadata have everything a preview
+    // needs in <head>, so reading stops once it ends. Otherwise the body is
+    // read as far as the limit allows, for JSON-LD scripts placed there.
+    let mut head_ended = false;
+    let mut has_title = false;
+    let body = fetch::read_body_until(response, *FETCH_MAX_HTML_BYTES, |data| {
+        if !head_ended {
+            has_title |= TITLE_METAS
+                .iter()
+                .any(|meta| contains_ignore_case(data, meta));
+            head_ended = contains_ignore_case(data, HEAD_END);
+        }
+        match (head_ended, has_title) {
+            (true, true) => ReadProgress::Complete,
+            (true, false) => ReadProgress::Sufficient,
+            _ => ReadProgress::Incomplete,
+        }
+    })
+    .await?;
+    let html = text::decode_html(&body, charset.as_deref());
 
     let document = Html::parse_document(&html);
-    let title = extract_meta_content(&document, &["og:title", "twitter:title"])
-        .or_else(|| extract_title(&document));
+    let mut preview = extract_preview(&document, url);
+    let oembed_endpoint =
+        oembed::discover_endpoint(&document, url).or_else(|| oembed::find_provider_endpoint(url));
+    // scraper's Html is not Send, so drop it before awaiting to keep the future Send
+    drop(document);
+
+    if let Some(endpoint) = oembed_endpoint {
+        match oembed::fetch_embed(&endpoint).await {
+            Ok(embed) => {
+                preview.author = preview.author.or_else(|| embed.author_name.clone());
+                preview.embed = Some(embed);
+            }
+            Err(e) => warn!("Failed to fetch oEmbed for {}: {}", url, e),
+        }
+    }
+    Ok((finish_preview(preview).await, max_age))
+}
+
+fn contains_ignore_case(data: &[u8], needle: &[u8]) -> bool {
+    data.windows(needle.len())
+        .any(|window| window.eq_ignore_ascii_case(needle))
+}
+
+async fn finish_preview(preview: LinkPreview) -> LinkPreview {
+    let mut preview = sanitize_preview(preview);
+    if *PREVIEW_REHOST_IMAGES && let Err(e) = rehost_image(&mut preview).await {
+        warn!("Failed to rehost preview image for {}: {}", preview.url, e);
+    }
+    preview
+}
+
+/// Copies the preview image into the CDN so clients don't load it from the
+/// origin, scaling it down to at most `PREVIEW_REHOST_MAX_WIDTH` pixels wide.
+async fn rehost_image(preview: &mut LinkPreview) -> Result<()> {
+    let Some(url) = &preview.image else {
+        return Ok(());
+    };
+    let image_bytes = preview_image::fetch_image(url).await?;
+    let (width, _) = preview_image::image_dimensions(&image_bytes)?;
+    // Rehosted images are served to any client, so stick to universally
+    // supported formats
+    let transform = TransformOptions {
+        width: Some(width.min(*PREVIEW_REHOST_MAX_WIDTH)),
+        ..Default::default()
+    };
+    let (resized, format) =
+        preview_image::resize_bytes(image_bytes, &transform, &OutputOptions::default()).await?;
+    let image = preview_image::analyze(resized.clone()).await?;
+    let hash = derivative::store(&resized, format.content_type()).await?;
+    preview.image_hash = Some(hash);
+    preview.image_width = Some(image.width);
+    preview.image_height = Some(image.height);
+    preview.image_blurhash = image.blurhash;
+    preview.image_thumbhash = image.thumbhash;
+    Ok(())
+}
+
+/// Points a rehosted image at a signed CDN URL. Signing happens per response
+/// so cached previews never hand out expired URLs.
+fn sign_image(preview: &mut LinkPreview) {
+    if let Some(url) = preview
+        .image_hash
+        .as_deref()
+        .and_then(derivative::signed_url)
+    {
+        preview.image = Some(url);
+    }
+}
+
+fn clean_field(value: Option<String>, max_chars: usize) -> Option<String> {
+    value.and_then(|value| text::clean_text(&value, max_chars))
+}
+
+fn limit_url(url: Option<String>) -> Option<String> {
+    url.filter(|url| url.len() <= MAX_URL_LENGTH)
+}
+
+fn limit_media(media: Option<PreviewMedia>) -> Option<PreviewMedia> {
+    let media = media?;
+    Some(PreviewMedia {
+        url: limit_url(Some(media.url))?,
+        mime_type: clean_field(media.mime_type, MAX_SHORT_TEXT_CHARS),
+        ..media
+    })
+}
+
+/// Strips markup from text fields, normalizes their whitespace and caps the
+/// length of every field, since all of them come from untrusted pages.
+fn sanitize_preview(preview: LinkPreview) -> LinkPreview {
+    LinkPreview {
+        url: preview.url,
+        kind: preview.kind,
+        mime_type: clean_field(preview.mime_type, MAX_SHORT_TEXT_CHARS),
+        size: preview.size,
+        canonical_url: limit_url(preview.canonical_url),
+        title: clean_field(preview.title, MAX_TITLE_CHARS),
+        description: clean_field(preview.description, MAX_DESCRIPTION_CHARS),
+        image: limit_url(preview.image),
+        image_hash: preview.image_hash,
+        image_width: preview.image_width,
+        image_height: preview.image_height,
+        image_blurhash: preview.image_blurhash,
+        image_thumbhash: preview.image_thumbhash,
+        image_alt: clean_field(preview.image_alt, MAX_SHORT_TEXT_CHARS),
+        video: limit_media(preview.video),
+        audio: limit_media(preview.audio),
+        site_name: clean_field(preview.site_name, MAX_SHORT_TEXT_CHARS),
+        favicon: limit_url(preview.favicon),
+        theme_color: clean_field(preview.theme_color, MAX_SHORT_TEXT_CHARS),
+        published_time: clean_field(preview.published_time, MAX_SHORT_TEXT_CHARS),
+        author: clean_field(preview.author, MAX_SHORT_TEXT_CHARS),
+        embed: preview.embed.map(|embed| Embed {
+            kind: embed.kind,
+            url: limit_url(embed.url),
+            html: embed
+                .html
+                .filter(|html| html.len() <= MAX_EMBED_HTML_LENGTH),
+            iframe_url: limit_url(embed.iframe_url),
+            width: embed.width,
+            height: embed.height,
+            title: clean_field(embed.title, MAX_TITLE_CHARS),
+            author_name: clean_field(embed.author_name, MAX_SHORT_TEXT_CHARS),
+            author_url: limit_url(embed.author_url),
+            provider_name: clean_field(embed.provider_name, MAX_SHORT_TEXT_CHARS),
+            provider_url: limit_url(embed.provider_url),
+            thumbnail_url: limit_url(embed.thumbnail_url),
+        }),
+    }
+}
+
+/// Builds a preview for a URL serving a file rather than a page. Images are
+/// measured from their header without downloading the whole file.
+async fn media_preview(
+    response: reqwest::Response,
+    url: &str,
+    content_type: String,
+) -> Result<LinkPreview> {
+    let kind = match content_type.split('/').next() {
+        Some("image") => PreviewKind::Image,
+        Some("video") => PreviewKind::Video,
+        Some("audio") => PreviewKind::Audio,
+        _ => PreviewKind::File,
+    };
+    let size = response.content_length();
+    let title = response
+        .url()
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            percent_encoding::percent_decode_str(name)
+                .decode_utf8_lossy()
+                .into_owned()
+        });
+
+    let (mut image_width, mut image_height) = (None, None);
+    if kind == PreviewKind::Image {
+        let header = fetch::read_prefix(response, IMAGE_HEADER_BYTES).await?;
+        match ImageReader::new(Cursor::new(&header))
+            .with_guessed_format()
+            .map_err(anyhow::Error::from)
+            .and_then(|reader| Ok(reader.into_dimensions()?))
+        {
+            Ok((width, height)) => {
+                image_width = Some(width);
+                image_height = Some(height);
+            }
+            Err(e) => warn!("Failed to read image dimensions for {}: {}", url, e),
+        }
+    }
+
+    Ok(LinkPreview {
+        url: url.to_string(),
+        kind,
+        mime_type: Some(content_type),
+        size,
+        canonical_url: None,
+        title,
+        description: None,
+        image: (kind == PreviewKind::Image).then(|| url.to_string()),
+        image_hash: None,
+        image_width,
+        image_height,
+        image_blurhash: None,
+        image_thumbhash: None,
+        image_alt: None,
+        video: None,
+        audio: None,
+        site_name: None,
+        favicon: None,
+        theme_color: None,
+        published_time: None,
+        author: None,
+        embed: None,
+    })
+}
+
+fn extract_preview(document: &Html, url: &str) -> LinkPreview {
+    // JSON-LD is only consulted for fields the meta tags leave empty
+    let json_ld = extract_json_ld(document);
+
+    let title = extract_meta_content(document, &["og:title", "twitter:title"])
+        .or_else(|| json_ld.as_ref().and_then(|ld| ld.title.clone()))
+        .or_else(|| extract_title(document));
     let description = extract_meta_content(
-        &document,
+        document,
         &["og:description", "twitter:description", "description"],
+    )
+    .or_else(|| json_ld.as_ref().and_then(|ld| ld.description.clone()));
+    let meta_image = extract_meta_content(
+        document,
+        &[
+            "og:image:secure_url",
+            "og:image",
+            "og:image:url",
+            "twitter:image",
+        ],
     );
-    let image = extract_meta_content(&document, &["og:image", "twitter:image"])
+    // Dimensions and alt text describe the meta image, not a JSON-LD fallback
+    let (image_width, image_height, image_alt) = match meta_image {
+        Some(_) => (
+            extract_meta_number(document, &["og:image:width"]),
+            extract_meta_number(document, &["og:image:height"]),
+            extract_meta_content(document, &["og:image:alt", "twitter:image:alt"]),
+        ),
+        None => (None, None, None),
+    };
+    let image = meta_image
+        .or_else(|| json_ld.as_ref().and_then(|ld| ld.image.clone()))
         .map(|img| resolve_url(url, &img));
-    let site_name = extract_meta_content(&document, &["og:site_name", "twitter:site"]);
+    let site_name = extract_meta_content(document, &["og:site_name", "twitter:site"])
+        .or_else(|| json_ld.as_ref().and_then(|ld| ld.publisher.clone()));
+    let canonical_url = extract_link_href(document, &["canonical"])
+        .or_else(|| extract_meta_content(document, &["og:url"]))
+        .map(|canonical| resolve_url(url, &canonical));
+    let favicon = extract_favicon(document, url);
+    let published_time = extract_meta_content(
+        document,
+        &["article:published_time", "og:published_time", "date"],
+    )
+    .or_else(|| json_ld.as_ref().and_then(|ld| ld.published_time.clone()));
+    let author = extract_meta_content(document, &["author", "article:author"])
+        .or_else(|| json_ld.as_ref().and_then(|ld| ld.author.clone()));
 
-    Ok(LinkPreview {
+    LinkPreview {
         url: url.to_string(),
+        kind: PreviewKind::Page,
+        mime_type: None,
+        size: None,
+        canonical_url,
         title,
         description,
         image,
+        image_hash: None,
+        image_width,
+        image_height,
+        image_blurhash: None,
+        image_thumbhash: None,
+        image_alt,
+        video: extract_media(document, url, "og:video")
+            .or_else(|| extract_media(document, url, "twitter:player")),
+        audio: extract_media(document, url, "og:audio"),
         site_name,
-    })
+        favicon,
+        theme_color: extract_meta_content(document, &["theme-color"]),
+        published_time,
+        author,
+        embed: None,
+    }
 }
 
 fn extract_meta_content(document: &Html, properties: &[&str]) -> Option<String> {
@@ -80,12 +421,175 @@ fn extract_meta_content(document: &Html, properties: &[&str]) -> Option<String>
     None
 }
 
+fn extract_meta_number(document: &Html, properties: &[&str]) -> Option<u32> {
+    extract_meta_content(document, properties)?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Extracts an `og:video`/`og:audio` style structured property, or a Twitter
+/// player card for `twitter:player`.
+fn extract_media(document: &Html, base: &str, property: &str) -> Option<PreviewMedia> {
+    let url = extract_meta_content(
+        document,
+        &[
+            &format!("{}:secure_url", property),
+            property,
+            &format!("{}:url", property),
+        ],
+    )?;
+    Some(PreviewMedia {
+        url: resolve_url(base, &url),
+        mime_type: extract_meta_content(document, &[&format!("{}:type", property)]),
+        width: extract_meta_number(document, &[&format!("{}:width", property)]),
+        height: extract_meta_number(document, &[&format!("{}:height", property)]),
+    })
+}
+
+fn extract_link_href(document: &Html, rels: &[&str]) -> Option<String> {
+    let selector = Selector::parse("link[rel][href]").ok()?;
+    for rel in rels {
+        let found = document.select(&selector).find(|element| {
+            element.value().attr("rel").is_some_and(|value| {
+                value
+                    .split_whitespace()
+                    .any(|r| r.eq_ignore_ascii_case(rel))
+            })
+        });
+        if let Some(href) = found.and_then(|element| element.value().attr("href"))
+            && !href.trim().is_empty()
+        {
+            return Some(href.trim().to_string());
+        }
+    }
+    None
+}
+
+/// Picks the largest declared icon, preferring apple-touch-icon over icon,
+/// and falls back to /favicon.ico.
+fn extract_favicon(document: &Html, base: &str) -> Option<String> {
+    let selector = Selector::parse("link[rel][href]").ok()?;
+    let mut best: Option<(bool, u32, &str)> = None;
+    for element in document.select(&selector) {
+        let rels = element
+            .value()
+            .attr("rel")
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let is_touch_icon = rels
+            .split_whitespace()
+            .any(|rel| rel == "apple-touch-icon" || rel == "apple-touch-icon-precomposed");
+        if !is_touch_icon && !rels.split_whitespace().any(|rel| rel == "icon") {
+            continue;
+        }
+        let size = element
+            .value()
+            .attr("sizes")
+            .and_then(|sizes| sizes.split_whitespace().next())
+            .and_then(|size| size.split(['x', 'X']).next())
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(0);
+        let href = element.value().attr("href").unwrap_or_default().trim();
+        if !href.is_empty()
+            && best.is_none_or(|(best_touch, best_size, _)| {
+                (is_touch_icon, size) > (best_touch, best_size)
+            })
+        {
+            best = Some((is_touch_icon, size, href));
+        }
+    }
+    Some(resolve_url(
+        base,
+        best.map_or("/favicon.ico", |(_, _, href)| href),
+    ))
+}
+
+#[derive(Debug, Default)]
+struct JsonLdMetadata {
+    title: Option<String>,
+    description: Option<String>,
+    image: Option<String>,
+    publisher: Option<String>,
+    published_time: Option<String>,
+    author: Option<String>,
+}
+
+/// Reads schema.org metadata from `application/ld+json` scripts. Scripts in
+/// the body are only seen for pages without Open Graph or Twitter titles,
+/// and only within `FETCH_MAX_HTML_BYTES`.
+fn extract_json_ld(document: &Html) -> Option<JsonLdMetadata> {
+    let selector = Selector::parse(r#"script[type="application/ld+json"]"#).ok()?;
+    let mut metadata = JsonLdMetadata::default();
+    let mut found = false;
+    for element in document.select(&selector) {
+        let Ok(value) = serde_json::from_str::<Value>(&element.text().collect::<String>()) else {
+            continue;
+        };
+        for item in json_ld_items(&value) {
+            found = true;
+            metadata.title = metadata
+                .title
+                .or_else(|| json_ld_text(item.get("headline").or_else(|| item.get("name"))));
+            metadata.description = metadata
+                .description
+                .or_else(|| json_ld_text(item.get("description")));
+            metadata.image = metadata.image.or_else(|| json_ld_url(item.get("image")));
+            metadata.publisher = metadata
+                .publisher
+                .or_else(|| json_ld_text(item.get("publisher")));
+            metadata.published_time = metadata
+                .published_time
+                .or_else(|| json_ld_text(item.get("datePublished")));
+            metadata.author = metadata.author.or_else(|| json_ld_text(item.get("author")));
+        }
+    }
+    found.then_some(metadata)
+}
+
+// Flattens top-level arrays and @graph containers into individual objects
+fn json_ld_items(value: &Value) -> Vec<&Value> {
+    match value {
+        Value::Array(items) => items.iter().flat_map(json_ld_items).collect(),
+        Value::Object(object) => match object.get("@graph") {
+            Some(graph) => json_ld_items(graph),
+            None => vec![value],
+        },
+        _ => Vec::new(),
+    }
+}
+
+// Values may be plain strings, objects with a name, or lists of either.
+// Strings are read from the raw script, so entities and tags are still encoded
+fn json_ld_text(value: Option<&Value>) -> Option<String> {
+    match value? {
+        Value::String(text) if !text.trim().is_empty() => {
+            Some(text::markup_to_text(text).trim().to_string())
+        }
+        Value::Object(object) => json_ld_text(object.get("name")),
+        Value::Array(items) => items.iter().find_map(|item| json_ld_text(Some(item))),
+        _ => None,
+    }
+}
+
+// Images may be URLs, ImageObjects, or lists of either
+fn json_ld_url(value: Option<&Value>) -> Option<String> {
+    match value? {
+        Value::String(url) if !url.trim().is_empty() => Some(url.trim().to_string()),
+        Value::Object(object) => {
+            json_ld_url(object.get("url").or_else(|| object.get("contentUrl")))
+        }
+        Value::Array(items) => items.iter().find_map(|item| json_ld_url(Some(item))),
+        _ => None,
+    }
+}
+
 fn extract_title(document: &Html) -> Option<String> {
     let selector = Selector::parse("title").ok()?;
     document
         .select(&selector)
         .next()
-        .map(|element| element.inner_html().trim().to_string())
+        .map(|element| element.text().collect())
 }
 
 fn resolve_url(base: &str, relative: &str) -> String {
@@ -107,13 +611,79 @@ pub struct LinkPreviewQuery {
 
 pub async fn get_link_preview(query: web::Query<LinkPreviewQuery>) -> ActixResult<HttpResponse> {
     info!("Fetching link preview for: {}", query.url);
-    match fetch_preview(&query.url).await {
-        Ok(preview) => Ok(HttpResponse::Ok().json(preview)),
-        Err(e) => {
-            error!("Link preview error: {}", e);
+    match preview_cache::get_preview(&query.url).await {
+        CachedPreview::Found { mut preview } => {
+            sign_image(&mut preview);
+            Ok(HttpResponse::Ok().json(preview))
+        }
+        CachedPreview::Rejected { error } => {
+            warn!("Link preview rejected: {}", error);
+            Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: format!("URL not allowed: {}", error),
+            }))
+        }
+        CachedPreview::Failed { error } => {
+            error!("Link preview error: {}", error);
             Ok(HttpResponse::InternalServerError().json(ErrorResponse {
-                error: format!("Failed to fetch preview: {}", e),
+                error: format!("Failed to fetch preview: {}", error),
             }))
         }
     }
 }
+
+#[derive(Deserialize)]
+pub struct BatchPreviewRequest {
+    urls: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct BatchPreviewResult {
+    url: String,
+    #[serde(flatten)]
+    result: CachedPreview,
+}
+
+pub async fn get_link_preview_batch(
+    body: web::Json<BatchPreviewRequest>,
+) -> ActixResult<HttpResponse> {
+    let urls = body.into_inner().urls;
+    info!("Fetching {} link previews", urls.len());
+    if urls.len() > *PREVIEW_BATCH_MAX_URLS {
+        return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+            error: format!(
+                "At most {} URLs can be previewed at once",
+                *PREVIEW_BATCH_MAX_URLS
+            ),
+        }));
+    }
+
+    // Limit how many requests a single batch sends to the same host
+    let mut host_limits: HashMap<String, Arc<Semaphore>> = HashMap::new();
+    let deadline = Instant::now() + Duration::from_secs(*PREVIEW_BATCH_TIMEOUT_SECONDS);
+    let previews = urls.into_iter().map(|url| {
+        let host = url::Url::parse(&url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or_default();
+        let limit = host_limits
+            .entry(host)
+            .or_insert_with(|| Arc::new(Semaphore::new(*PREVIEW_BATCH_HOST_CONCURRENCY)))
+            .clone();
+        async move {
+            let mut result = timeout_at(deadline, async {
+                let _permit = limit.acquire().await;
+                preview_cache::get_preview(&url).await
+            })
+            .await
+            .unwrap_or_else(|_| CachedPreview::Failed {
+                error: "Timed out".to_string(),
+            });
+            if let CachedPreview::Found { preview } = &mut result {
+                sign_image(preview);
+            }
+            BatchPreviewResult { url, result }
+        }
+    });
+    let results = join_all(previews).await;
+    Ok(HttpResponse::Ok().json(results))
+}
@@ -1,46 +1,910 @@
-use actix_web::{HttpResponse, Result as ActixResult, web};
-use anyhow::{Context, Result};
+use actix_web::{
+    HttpRequest, HttpResponse, Result as ActixResult,
+    http::header::{ACCEPT, VARY},
+    web,
+};
+use anyhow::{Context, Result, anyhow};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
 use bytes::Bytes;
-use image::{GenericImageView, ImageFormat, imageops::FilterType};
-use log::{debug, error, info};
+use image::{
+    DynamicImage, ExtendedColorType, GenericImageView, ImageDecoder, ImageFormat, ImageReader,
+    Limits, RgbImage, Rgba, RgbaImage,
+    codecs::{avif::AvifEncoder, jpeg::JpegEncoder, webp::WebPEncoder},
+    imageops::{self, FilterType},
+    metadata::Orientation,
+};
+use lazy_static::lazy_static;
+use log::{debug, error, info, warn};
 use serde::Deserialize;
+use std::fmt;
 use std::io::Cursor;
+use thiserror::Error;
+use tokio::sync::Semaphore;
 
-use crate::{ErrorResponse, routes::preview::HTTP_CLIENT};
+use crate::{
+    ErrorResponse,
+    animation::{self, Animation, AnimationFrame},
+    database::ImageMetadata,
+    environment::{
+        FETCH_MAX_IMAGE_BYTES, IMAGE_MAX_DECODE_BYTES, IMAGE_MAX_OUTPUT_DIMENSION,
+        IMAGE_MAX_SOURCE_PIXELS, IMAGE_WORKER_THREADS,
+    },
+    fetch,
+};
 
-pub async fn resize_from_url(url: &str, width: Option<u32>, height: Option<u32>) -> Result<Bytes> {
-    let response = HTTP_CLIENT
-        .get(url)
-        .send()
-        .await
-        .context("Failed to fetch image")?;
-    let image_bytes = response
-        .bytes()
+// Some hosts serve images without a specific content type
+const IMAGE_CONTENT_TYPES: &[&str] = &["image/", "application/octet-stream"];
+// rav1e speed from 1 (slowest) to 10; encoding at lower speeds takes seconds
+const AVIF_SPEED: u8 = 8;
+// Larger images take too long to encode as AVIF even at `AVIF_SPEED`, so
+// they are negotiated to WebP or JPEG instead
+const AVIF_MAX_PIXELS: u64 = 2_000_000;
+// Images are scaled down to at most this size before computing their dominant
+// color and placeholders; ThumbHash accepts no larger input
+const THUMBNAIL_SIZE: u32 = 100;
+const BLURHASH_COMPONENTS: u32 = 4;
+const MAX_DPR: f32 = 4.0;
+
+lazy_static! {
+    static ref IMAGE_WORKERS: Semaphore = Semaphore::new(*IMAGE_WORKER_THREADS);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Png,
+    #[serde(alias = "jpg")]
+    Jpeg,
+    Webp,
+    Avif,
+    Gif,
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg => "jpeg",
+            OutputFormat::Webp => "webp",
+            OutputFormat::Avif => "avif",
+            OutputFormat::Gif => "gif",
+        };
+        f.write_str(name)
+    }
+}
+
+impl OutputFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "image/png",
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Webp => "image/webp",
+            OutputFormat::Avif => "image/avif",
+            OutputFormat::Gif => "image/gif",
+        }
+    }
+
+    pub fn supports_animation(&self) -> bool {
+        matches!(self, OutputFormat::Webp | OutputFormat::Gif)
+    }
+
+    // Only used by the lossy encoders
+    fn default_quality(&self) -> u8 {
+        match self {
+            OutputFormat::Jpeg => 82,
+            OutputFormat::Webp => 80,
+            OutputFormat::Avif => 60,
+            OutputFormat::Png | OutputFormat::Gif => 100,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    // Scale to fit within the box, then pad it with the background
+    Contain,
+    // Scale to fill the box, cropping around the gravity
+    Cover,
+    // Stretch to exactly the requested dimensions
+    Fill,
+    // Scale to fit within the box
+    #[default]
+    Inside,
+    // Scale until the box is covered, without cropping
+    Outside,
+}
+
+impl fmt::Display for Fit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Fit::Contain => "contain",
+            Fit::Cover => "cover",
+            Fit::Fill => "fill",
+            Fit::Inside => "inside",
+            Fit::Outside => "outside",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Where crops and padding are anchored: a compass direction or a focal
+/// point given as `x,y` fractions of the image size.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Gravity(f32, f32);
+
+impl Default for Gravity {
+    fn default() -> Self {
+        Gravity(0.5, 0.5)
+    }
+}
+
+impl Gravity {
+    fn focus(&self) -> (f32, f32) {
+        (self.0, self.1)
+    }
+}
+
+// Compass directions are written as their focal point
+impl fmt::Display for Gravity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{}", self.0, self.1)
+    }
+}
+
+impl TryFrom<String> for Gravity {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let gravity = match value.as_str() {
+            "center" => Gravity(0.5, 0.5),
+            "north" => Gravity(0.5, 0.0),
+            "south" => Gravity(0.5, 1.0),
+            "east" => Gravity(1.0, 0.5),
+            "west" => Gravity(0.0, 0.5),
+            "northeast" => Gravity(1.0, 0.0),
+            "northwest" => Gravity(0.0, 0.0),
+            "southeast" => Gravity(1.0, 1.0),
+            "southwest" => Gravity(0.0, 1.0),
+            focal_point => {
+                let (x, y) = focal_point
+                    .split_once(',')
+                    .and_then(|(x, y)| {
+                        Some((x.trim().parse::<f32>().ok()?, y.trim().parse::<f32>().ok()?))
+                    })
+                    .ok_or_else(|| format!("Invalid gravity: {}", value))?;
+                if !(0.0..=1.0).contains(&x) || !(0.0..=1.0).contains(&y) {
+                    return Err("Focal point coordinates must be between 0 and 1".to_string());
+                }
+                Gravity(x, y)
+            }
+        };
+        Ok(gravity)
+    }
+}
+
+/// An RGBA color parsed from hex notation (`rgb`, `rrggbb` or `rrggbbaa`,
+/// with or without a leading `#`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Color([u8; 4]);
+
+impl Color {
+    const WHITE: Color = Color([255, 255, 255, 255]);
+    const TRANSPARENT: Color = Color([0, 0, 0, 0]);
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+impl TryFrom<String> for Color {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let hex = value.trim_start_matches('#');
+        let expanded = match hex.len() {
+            3 => hex.chars().flat_map(|c| [c, c]).collect::<String>() + "ff",
+            6 => format!("{}ff", hex),
+            8 => hex.to_string(),
+            _ => return Err(format!("Invalid color: {}", value)),
+        };
+        let bytes = hex::decode(&expanded).map_err(|_| format!("Invalid color: {}", value))?;
+        Ok(Color([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+/// Query parameters controlling how an image is resized and encoded.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TransformOptions {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    #[serde(default)]
+    pub fit: Fit,
+    #[serde(default)]
+    pub gravity: Gravity,
+    // Padding for `contain` and the backdrop for formats without alpha
+    pub background: Option<Color>,
+    // Device pixel ratio multiplying width and height
+    pub dpr: Option<f32>,
+    pub format: Option<OutputFormat>,
+    // 1 to 100, ignored by lossless formats
+    pub quality: Option<u8>,
+    // Render only the first frame of animated images
+    #[serde(default)]
+    pub poster: bool,
+}
+
+impl TransformOptions {
+    pub fn validate(&self) -> Result<(), String> {
+        if self
+            .quality
+            .is_some_and(|quality| !(1..=100).contains(&quality))
+        {
+            return Err("Quality must be between 1 and 100".to_string());
+        }
+        if self.dpr.is_some_and(|dpr| !(dpr > 0.0 && dpr <= MAX_DPR)) {
+            return Err(format!(
+                "DPR must be greater than 0 and at most {}",
+                MAX_DPR
+            ));
+        }
+        if self.width == Some(0) || self.height == Some(0) {
+            return Err("Dimensions must be greater than 0".to_string());
+        }
+        let dpr = self.dpr.unwrap_or(1.0);
+        if [self.width, self.height]
+            .into_iter()
+            .flatten()
+            .any(|dimension| dimension as f32 * dpr > *IMAGE_MAX_OUTPUT_DIMENSION as f32)
+        {
+            return Err(format!(
+                "Dimensions must be at most {} pixels",
+                *IMAGE_MAX_OUTPUT_DIMENSION
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the parameters as a canonical query string, omitting defaults,
+    /// so equivalent requests share signatures and cached variants.
+    pub fn normalized(&self) -> String {
+        let mut params: Vec<(&str, String)> = Vec::new();
+        if let Some(width) = self.width {
+            params.push(("width", width.to_string()));
+        }
+        if let Some(height) = self.height {
+            params.push(("height", height.to_string()));
+        }
+        if self.fit != Fit::default() {
+            params.push(("fit", self.fit.to_string()));
+        }
+        if self.gravity != Gravity::default() {
+            params.push(("gravity", self.gravity.to_string()));
+        }
+        if let Some(background) = self.background {
+            params.push(("background", background.to_string()));
+        }
+        if let Some(dpr) = self.dpr.filter(|dpr| *dpr != 1.0) {
+            params.push(("dpr", dpr.to_string()));
+        }
+        if let Some(format) = self.format {
+            params.push(("format", format.to_string()));
+        }
+        if let Some(quality) = self.quality {
+            params.push(("quality", quality.to_string()));
+        }
+        if self.poster {
+            params.push(("poster", "true".to_string()));
+        }
+        params
+            .into_iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+
+    /// True if the image can be served without re-encoding it.
+    pub fn is_empty(&self) -> bool {
+        self.width.is_none()
+            && self.height.is_none()
+            && self.format.is_none()
+            && self.quality.is_none()
+            && !self.poster
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ImageLimitError {
+    #[error("Image has {0} pixels, more than the limit of {1}")]
+    TooManyPixels(u64, u64),
+    #[error("Output size {0}x{1} exceeds the limit of {2} pixels per side")]
+    OutputTooLarge(u32, u32, u32),
+    #[error("Animation has more than {0} frames")]
+    TooManyFrames(usize),
+    #[error("Animation has more than {0} pixels across all frames")]
+    AnimationTooLarge(u64),
+}
+
+/// Returns the processing limit behind this error, if any, so handlers can
+/// reject oversized images as bad requests.
+pub fn limit_exceeded(error: &anyhow::Error) -> Option<&ImageLimitError> {
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<ImageLimitError>())
+}
+
+/// How a processed image is encoded: either an explicitly requested format,
+/// or the best format the client accepts for the image's content.
+#[derive(Debug, Clone, Default)]
+pub struct OutputOptions {
+    pub format: Option<OutputFormat>,
+    pub accepts_webp: bool,
+    pub accepts_avif: bool,
+    // 1 to 100, ignored by lossless formats
+    pub quality: Option<u8>,
+}
+
+impl OutputOptions {
+    pub fn new(req: &HttpRequest, transform: &TransformOptions) -> Self {
+        let accept = req
+            .headers()
+            .get(ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .unwrap_or_default();
+        Self {
+            format: transform.format,
+            accepts_webp: accepts(accept, "image/webp"),
+            accepts_avif: accepts(accept, "image/avif"),
+            quality: transform.quality,
+        }
+    }
+
+    /// True if the output depends on the `Accept` header.
+    pub fn is_negotiated(&self) -> bool {
+        self.format.is_none()
+    }
+
+    /// Animations keep moving unless a static format was requested
+    /// explicitly; GIF is the fallback every client understands.
+    fn choose_animated_format(&self) -> OutputFormat {
+        match self.format {
+            Some(format) => format,
+            None if self.accepts_webp => OutputFormat::Webp,
+            None => OutputFormat::Gif,
+        }
+    }
+
+    /// Describes the negotiated formats, so outputs for clients accepting
+    /// different formats can be told apart when caching.
+    pub fn negotiation_key(&self) -> String {
+        if !self.is_negotiated() {
+            return String::new();
+        }
+        let mut accepted = Vec::new();
+        if self.accepts_webp {
+            accepted.push("webp");
+        }
+        if self.accepts_avif {
+            accepted.push("avif");
+        }
+        format!("accept={}", accepted.join(","))
+    }
+
+    /// Photos are encoded lossy, preferring AVIF unless the image is too
+    /// large to encode quickly, then WebP. Images with transparency are
+    /// encoded losslessly unless a quality was requested.
+    fn choose_format(&self, has_transparency: bool, width: u32, height: u32) -> OutputFormat {
+        let pixels = width as u64 * height as u64;
+        match self.format {
+            Some(format) => format,
+            None if has_transparency && self.accepts_webp => OutputFormat::Webp,
+            None if has_transparency => OutputFormat::Png,
+            None if self.accepts_avif && pixels <= AVIF_MAX_PIXELS => OutputFormat::Avif,
+            None if self.accepts_webp => OutputFormat::Webp,
+            None => OutputFormat::Jpeg,
+        }
+    }
+}
+
+/// Returns true if the `Accept` header lists the media type with a non-zero
+/// quality. Wildcards are ignored since they don't imply support for newer
+/// formats.
+fn accepts(accept: &str, media_type: &str) -> bool {
+    accept.split(',').any(|entry| {
+        let mut params = entry.split(';').map(str::trim);
+        params
+            .next()
+            .is_some_and(|accepted| accepted.eq_ignore_ascii_case(media_type))
+            && params.all(|param| match param.split_once('=') {
+                Some(("q", value)) => value.parse::<f32>().is_ok_and(|q| q > 0.0),
+                _ => true,
+            })
+    })
+}
+
+/// Downloads an image from a user-supplied URL through the safe fetcher.
+pub async fn fetch_image(url: &str) -> Result<Bytes> {
+    let response = fetch::get(url).await.context("Failed to fetch image")?;
+    fetch::check_content_type(&response, IMAGE_CONTENT_TYPES)?;
+    let image_bytes = fetch::read_body(response, *FETCH_MAX_IMAGE_BYTES)
         .await
         .context("Failed to read image bytes")?;
+    Ok(image_bytes)
+}
+
+pub async fn resize_from_url(
+    url: &str,
+    transform: &TransformOptions,
+    output: &OutputOptions,
+) -> Result<(Bytes, OutputFormat)> {
+    let image_bytes = fetch_image(url).await?;
+    resize_bytes(image_bytes, transform, output).await
+}
+
+/// Resizes and re-encodes the image on the image worker pool, keeping CPU
+/// heavy work off the async executor.
+pub async fn resize_bytes(
+    image_bytes: Bytes,
+    transform: &TransformOptions,
+    output: &OutputOptions,
+) -> Result<(Bytes, OutputFormat)> {
+    let transform = transform.clone();
+    let output = output.clone();
+    run_blocking(move || process(image_bytes, &transform, &output)).await
+}
+
+/// Reads the dimensions, frame count, color type and dominant color of an
+/// image on the image worker pool.
+pub async fn analyze(image_bytes: Bytes) -> Result<ImageMetadata> {
+    run_blocking(move || analyze_image(&image_bytes)).await
+}
+
+fn analyze_image(image_bytes: &[u8]) -> Result<ImageMetadata> {
+    let (width, height) = image_dimensions(image_bytes)?;
+    let color_type = ImageReader::new(Cursor::new(image_bytes))
+        .with_guessed_format()?
+        .into_decoder()
+        .context("Failed to read image color type")?
+        .original_color_type();
+    let frame_count = animation::frame_count(image_bytes);
+    let mut metadata = ImageMetadata {
+        width,
+        height,
+        animated: frame_count > 1,
+        frame_count,
+        color_type: color_type_name(color_type).to_string(),
+        dominant_color: None,
+        blurhash: None,
+        thumbhash: None,
+    };
+
+    // Animations are described by their first frame. Images too large to
+    // decode keep what their header tells.
+    let img = match decode(image_bytes) {
+        Ok(img) => img,
+        Err(e) if is_refused(&e) => {
+            debug!("Not decoding image for analysis: {}", e);
+            return Ok(metadata);
+        }
+        Err(e) => return Err(e),
+    };
+    let thumbnail = if img.width() > THUMBNAIL_SIZE || img.height() > THUMBNAIL_SIZE {
+        img.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE).to_rgba8()
+    } else {
+        img.to_rgba8()
+    };
+    metadata.dominant_color = dominant_color(&thumbnail);
+    metadata.blurhash = Some(blurhash(&thumbnail)?);
+    metadata.thumbhash = Some(BASE64.encode(thumbhash::rgba_to_thumb_hash(
+        thumbnail.width() as usize,
+        thumbnail.height() as usize,
+        thumbnail.as_raw(),
+    )));
+    Ok(metadata)
+}
+
+/// Returns true if decoding was refused by one of the processing limits,
+/// rather than failing on invalid data.
+fn is_refused(error: &anyhow::Error) -> bool {
+    limit_exceeded(error).is_some()
+        || error.chain().any(|cause| {
+            matches!(
+                cause.downcast_ref::<image::ImageError>(),
+                Some(image::ImageError::Limits(_))
+            )
+        })
+}
+
+/// Encodes a BlurHash with up to 4 components along the longer side, and
+/// proportionally fewer along the shorter one.
+fn blurhash(thumbnail: &RgbaImage) -> Result<String> {
+    let (width, height) = thumbnail.dimensions();
+    let components = |side: u32, longest: u32| {
+        ((BLURHASH_COMPONENTS * side) as f32 / longest as f32)
+            .round()
+            .clamp(1.0, BLURHASH_COMPONENTS as f32) as u32
+    };
+    let longest = width.max(height);
+    blurhash::encode(
+        components(width, longest),
+        components(height, longest),
+        width,
+        height,
+        thumbnail.as_raw(),
+    )
+    .map_err(|e| anyhow!("Failed to encode BlurHash: {}", e))
+}
 
-    resize_bytes(image_bytes, width, height)
+fn color_type_name(color_type: ExtendedColorType) -> &'static str {
+    match color_type {
+        ExtendedColorType::A8 => "a8",
+        ExtendedColorType::L1 => "l1",
+        ExtendedColorType::La1 => "la1",
+        ExtendedColorType::L2 => "l2",
+        ExtendedColorType::La2 => "la2",
+        ExtendedColorType::L4 => "l4",
+        ExtendedColorType::La4 => "la4",
+        ExtendedColorType::L8 => "l8",
+        ExtendedColorType::La8 => "la8",
+        ExtendedColorType::L16 => "l16",
+        ExtendedColorType::La16 => "la16",
+        ExtendedColorType::Rgb1 => "rgb1",
+        ExtendedColorType::Rgb2 => "rgb2",
+        ExtendedColorType::Rgb4 => "rgb4",
+        ExtendedColorType::Rgb8 => "rgb8",
+        ExtendedColorType::Rgb16 => "rgb16",
+        ExtendedColorType::Rgb32F => "rgb32f",
+        ExtendedColorType::Rgba1 => "rgba1",
+        ExtendedColorType::Rgba2 => "rgba2",
+        ExtendedColorType::Rgba4 => "rgba4",
+        ExtendedColorType::Rgba8 => "rgba8",
+        ExtendedColorType::Rgba16 => "rgba16",
+        ExtendedColorType::Rgba32F => "rgba32f",
+        ExtendedColorType::Bgr8 => "bgr8",
+        ExtendedColorType::Bgra8 => "bgra8",
+        ExtendedColorType::Cmyk8 => "cmyk8",
+        _ => "unknown",
+    }
+}
+
+/// Returns the most common color as `#rrggbb`. Colors are bucketed to 4 bits
+/// per channel and the pixels of the largest bucket averaged. Returns None
+/// for fully transparent images.
+fn dominant_color(thumbnail: &RgbaImage) -> Option<String> {
+    let mut buckets = vec![(0u32, [0u32; 3]); 1 << 12];
+    for pixel in thumbnail.pixels() {
+        // Mostly transparent pixels don't contribute to how the image looks
+        if pixel[3] < 128 {
+            continue;
+        }
+        let index = ((pixel[0] as usize >> 4) << 8)
+            | ((pixel[1] as usize >> 4) << 4)
+            | (pixel[2] as usize >> 4);
+        let (count, sums) = &mut buckets[index];
+        *count += 1;
+        for channel in 0..3 {
+            sums[channel] += pixel[channel] as u32;
+        }
+    }
+    let (count, sums) = buckets.into_iter().max_by_key(|(count, _)| *count)?;
+    if count == 0 {
+        return None;
+    }
+    Some(format!(
+        "#{:02x}{:02x}{:02x}",
+        sums[0] / count,
+        sums[1] / count,
+        sums[2] / count
+    ))
+}
+
+/// Runs `f` on the blocking thread pool once one of the
+/// `IMAGE_WORKER_THREADS` slots is free.
+pub async fn run_blocking<F, T>(f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let _permit = IMAGE_WORKERS
+        .acquire()
+        .await
+        .context("Image worker pool closed")?;
+    tokio::task::spawn_blocking(f)
+        .await
+        .context("Image worker panicked")?
 }
 
-pub fn resize_bytes(image_bytes: Bytes, width: Option<u32>, height: Option<u32>) -> Result<Bytes> {
-    let img = image::load_from_memory(&image_bytes).context("Failed to decode image")?;
+/// Decodes the image after checking its declared dimensions, so files that
+/// claim huge sizes are rejected before any pixel buffer is allocated.
+fn decode(image_bytes: &[u8]) -> Result<DynamicImage> {
+    let (width, height) = ImageReader::new(Cursor::new(image_bytes))
+        .with_guessed_format()?
+        .into_dimensions()
+        .context("Failed to read image dimensions")?;
+    let pixels = width as u64 * height as u64;
+    if pixels > *IMAGE_MAX_SOURCE_PIXELS {
+        return Err(ImageLimitError::TooManyPixels(pixels, *IMAGE_MAX_SOURCE_PIXELS).into());
+    }
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(width);
+    limits.max_image_height = Some(height);
+    limits.max_alloc = Some(*IMAGE_MAX_DECODE_BYTES);
+    let mut reader = ImageReader::new(Cursor::new(image_bytes)).with_guessed_format()?;
+    reader.limits(limits);
+    let mut decoder = reader.into_decoder().context("Failed to decode image")?;
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let mut img = DynamicImage::from_decoder(decoder).context("Failed to decode image")?;
+    img.apply_orientation(orientation);
+    Ok(img)
+}
+
+/// Returns the dimensions of an image as displayed, after applying its EXIF
+/// orientation.
+pub fn image_dimensions(image_bytes: &[u8]) -> Result<(u32, u32)> {
+    let mut decoder = ImageReader::new(Cursor::new(image_bytes))
+        .with_guessed_format()?
+        .into_decoder()
+        .context("Failed to read image dimensions")?;
+    let (width, height) = decoder.dimensions();
+    match decoder.orientation().unwrap_or(Orientation::NoTransforms) {
+        Orientation::Rotate90
+        | Orientation::Rotate270
+        | Orientation::Rotate90FlipH
+        | Orientation::Rotate270FlipH => Ok((height, width)),
+        _ => Ok((width, height)),
+    }
+}
+
+fn check_output_dimensions(width: u32, height: u32) -> Result<(), ImageLimitError> {
+    if width > *IMAGE_MAX_OUTPUT_DIMENSION || height > *IMAGE_MAX_OUTPUT_DIMENSION {
+        return Err(ImageLimitError::OutputTooLarge(
+            width,
+            height,
+            *IMAGE_MAX_OUTPUT_DIMENSION,
+        ));
+    }
+    Ok(())
+}
+
+fn process(
+    image_bytes: Bytes,
+    transform: &TransformOptions,
+    output: &OutputOptions,
+) -> Result<(Bytes, OutputFormat)> {
+    if !transform.poster
+        && output
+            .format
+            .is_none_or(|format| format.supports_animation())
+        && let Some(animation) = animation::decode(&image_bytes)?
+    {
+        return process_animation(animation, transform, output);
+    }
+
+    let img = decode(&image_bytes)?;
     let (original_width, original_height) = img.dimensions();
     debug!(
         "Original image dimensions: {}x{}",
         original_width, original_height
     );
     let (target_width, target_height) =
-        calculate_dimensions(original_width, original_height, width, height);
-    debug!("Resizing to: {}x{}", target_width, target_height);
-    let resized = img.resize(target_width, target_height, FilterType::Lanczos3);
+        target_dimensions(original_width, original_height, transform)?;
+    let resized = apply_fit(&img, target_width, target_height, transform)?;
+
+    let transparent = has_transparency(&resized);
+    let format = output.choose_format(transparent, resized.width(), resized.height());
+    let background = transform.background.unwrap_or(Color::WHITE);
+    // Transparent images stay lossless as WebP unless a quality was asked for
+    let lossless = transparent && output.quality.is_none();
+    let output_bytes = encode(&resized, format, output.quality, lossless, background)?;
+    debug!(
+        "Resized image size: {} bytes ({:?})",
+        output_bytes.len(),
+        format
+    );
+    Ok((output_bytes, format))
+}
+
+/// Resizes every frame the same way and re-encodes the animation, keeping
+/// frame timing and the loop count.
+fn process_animation(
+    animation: Animation,
+    transform: &TransformOptions,
+    output: &OutputOptions,
+) -> Result<(Bytes, OutputFormat)> {
+    let (original_width, original_height) = animation.frames[0].image.dimensions();
+    debug!(
+        "Original animation: {}x{}, {} frames",
+        original_width,
+        original_height,
+        animation.frames.len()
+    );
+    let (target_width, target_height) =
+        target_dimensions(original_width, original_height, transform)?;
+    animation::check_budget(animation.frames.len(), target_width, target_height)?;
+    let frames = animation
+        .frames
+        .into_iter()
+        .map(|frame| {
+            let resized = apply_fit(
+                &DynamicImage::ImageRgba8(frame.image),
+                target_width,
+                target_height,
+                transform,
+            )?;
+            Ok(AnimationFrame {
+                image: resized.to_rgba8(),
+                delay_ms: frame.delay_ms,
+            })
+        })
+        .collect::<Result<Vec<_>, ImageLimitError>>()?;
+    let resized = Animation {
+        frames,
+        loop_count: animation.loop_count,
+    };
+
+    let format = output.choose_animated_format();
+    let output_bytes = match format {
+        OutputFormat::Gif => animation::encode_gif(&resized)?,
+        _ => animation::encode_webp(&resized)?,
+    };
+    debug!(
+        "Resized animation size: {} bytes ({:?})",
+        output_bytes.len(),
+        format
+    );
+    Ok((Bytes::from(output_bytes), format))
+}
+
+/// Applies the device pixel ratio and fills in a missing dimension from the
+/// aspect ratio, rejecting results beyond `IMAGE_MAX_OUTPUT_DIMENSION`.
+fn target_dimensions(
+    original_width: u32,
+    original_height: u32,
+    transform: &TransformOptions,
+) -> Result<(u32, u32), ImageLimitError> {
+    let dpr = transform.dpr.unwrap_or(1.0);
+    let scale =
+        |dimension: Option<u32>| dimension.map(|d| ((d as f32 * dpr).round() as u32).max(1));
+    let (target_width, target_height) = calculate_dimensions(
+        original_width,
+        original_height,
+        scale(transform.width),
+        scale(transform.height),
+    );
+    debug!(
+        "Resizing to: {}x{} ({:?})",
+        target_width, target_height, transform.fit
+    );
+    check_output_dimensions(target_width, target_height)?;
+    Ok((target_width, target_height))
+}
+
+fn apply_fit(
+    img: &DynamicImage,
+    width: u32,
+    height: u32,
+    transform: &TransformOptions,
+) -> Result<DynamicImage, ImageLimitError> {
+    let (original_width, original_height) = img.dimensions();
+    let scale_x = width as f64 / original_width as f64;
+    let scale_y = height as f64 / original_height as f64;
+    let scaled = |scale: f64| {
+        (
+            ((original_width as f64 * scale).round() as u32).max(1),
+            ((original_height as f64 * scale).round() as u32).max(1),
+        )
+    };
+    let (focus_x, focus_y) = transform.gravity.focus();
+    let resized = match transform.fit {
+        Fit::Fill => img.resize_exact(width, height, FilterType::Lanczos3),
+        Fit::Inside => img.resize(width, height, FilterType::Lanczos3),
+        Fit::Outside => {
+            let (scaled_width, scaled_height) = scaled(scale_x.max(scale_y));
+            check_output_dimensions(scaled_width, scaled_height)?;
+            img.resize_exact(scaled_width, scaled_height, FilterType::Lanczos3)
+        }
+        Fit::Cover => {
+            let (scaled_width, scaled_height) = scaled(scale_x.max(scale_y));
+            // The intermediate image can be far larger than the crop
+            check_output_dimensions(scaled_width, scaled_height)?;
+            let resized = img.resize_exact(scaled_width, scaled_height, FilterType::Lanczos3);
+            // Keep the focal point as close to the center of the crop as possible
+            let x = crop_offset(scaled_width, width, focus_x);
+            let y = crop_offset(scaled_height, height, focus_y);
+            resized.crop_imm(x, y, width, height)
+        }
+        Fit::Contain => {
+            let resized = img.resize(width, height, FilterType::Lanczos3);
+            let background = transform.background.unwrap_or(Color::TRANSPARENT);
+            let mut canvas = RgbaImage::from_pixel(width, height, Rgba(background.0));
+            let x = ((width - resized.width()) as f32 * focus_x).round() as i64;
+            let y = ((height - resized.height()) as f32 * focus_y).round() as i64;
+            imageops::overlay(&mut canvas, &resized.to_rgba8(), x, y);
+            DynamicImage::ImageRgba8(canvas)
+        }
+    };
+    Ok(resized)
+}
+
+fn crop_offset(scaled: u32, target: u32, focus: f32) -> u32 {
+    let max_offset = scaled.saturating_sub(target);
+    let offset = (scaled as f32 * focus - target as f32 / 2.0).round();
+    (offset.max(0.0) as u32).min(max_offset)
+}
+
+/// Returns true if the image has an alpha channel that is actually used.
+fn has_transparency(img: &DynamicImage) -> bool {
+    img.color().has_alpha() && img.to_rgba8().pixels().any(|pixel| pixel[3] < u8::MAX)
+}
+
+fn encode(
+    img: &DynamicImage,
+    format: OutputFormat,
+    quality: Option<u8>,
+    lossless: bool,
+    background: Color,
+) -> Result<Bytes> {
+    let quality = quality.unwrap_or_else(|| format.default_quality());
     let mut output = Cursor::new(Vec::new());
-    resized
-        .write_to(&mut output, ImageFormat::Png)
-        .context("Failed to encode resized image")?;
+    let result = match format {
+        OutputFormat::Png => img.write_to(&mut output, ImageFormat::Png),
+        OutputFormat::Gif => {
+            DynamicImage::ImageRgba8(img.to_rgba8()).write_to(&mut output, ImageFormat::Gif)
+        }
+        // JPEG has no alpha channel, so transparent areas get the background
+        OutputFormat::Jpeg => DynamicImage::ImageRgb8(flatten(img, background))
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut output, quality)),
+        OutputFormat::Webp if lossless => {
+            to_rgb_or_rgba(img).write_with_encoder(WebPEncoder::new_lossless(&mut output))
+        }
+        // image only encodes lossless WebP, so lossy WebP goes through libwebp
+        OutputFormat::Webp => {
+            output
+                .get_mut()
+                .extend_from_slice(&encode_lossy_webp(img, quality));
+            Ok(())
+        }
+        OutputFormat::Avif => to_rgb_or_rgba(img).write_with_encoder(
+            AvifEncoder::new_with_speed_quality(&mut output, AVIF_SPEED, quality),
+        ),
+    };
+    result.context("Failed to encode resized image")?;
+    Ok(Bytes::from(output.into_inner()))
+}
+
+fn encode_lossy_webp(img: &DynamicImage, quality: u8) -> Vec<u8> {
+    let (width, height) = img.dimensions();
+    let encoded = if img.color().has_alpha() {
+        webp::Encoder::from_rgba(&img.to_rgba8(), width, height).encode(quality as f32)
+    } else {
+        webp::Encoder::from_rgb(&img.to_rgb8(), width, height).encode(quality as f32)
+    };
+    encoded.to_vec()
+}
+
+fn flatten(img: &DynamicImage, background: Color) -> RgbImage {
+    if !img.color().has_alpha() {
+        return img.to_rgb8();
+    }
+    let [red, green, blue, _] = background.0;
+    let mut canvas =
+        RgbaImage::from_pixel(img.width(), img.height(), Rgba([red, green, blue, 255]));
+    imageops::overlay(&mut canvas, &img.to_rgba8(), 0, 0);
+    DynamicImage::ImageRgba8(canvas).to_rgb8()
+}
 
-    let output_bytes = Bytes::from(output.into_inner());
-    debug!("Resized image size: {} bytes", output_bytes.len());
-    Ok(output_bytes)
+// The WebP and AVIF encoders only accept 8-bit RGB(A)
+fn to_rgb_or_rgba(img: &DynamicImage) -> DynamicImage {
+    if img.color().has_alpha() {
+        DynamicImage::ImageRgba8(img.to_rgba8())
+    } else {
+        DynamicImage::ImageRgb8(img.to_rgb8())
+    }
 }
 
 fn calculate_dimensions(
@@ -67,28 +931,51 @@ fn calculate_dimensions(
 
 #[derive(Deserialize)]
 pub struct PreviewImageQuery {
-    width: Option<u32>,
-    height: Option<u32>,
     url: String,
 }
 
-pub async fn preview_image(query: web::Query<PreviewImageQuery>) -> ActixResult<HttpResponse> {
+pub async fn preview_image(
+    req: HttpRequest,
+    query: web::Query<PreviewImageQuery>,
+    transform: web::Query<TransformOptions>,
+) -> ActixResult<HttpResponse> {
     info!(
         "Resizing image: {} ({}x{})",
         query.url,
-        query.width.unwrap_or(0),
-        query.height.unwrap_or(0)
+        transform.width.unwrap_or(0),
+        transform.height.unwrap_or(0)
     );
-    if query.width.is_none() && query.height.is_none() {
+    if transform.width.is_none() && transform.height.is_none() {
         return Ok(HttpResponse::BadRequest().json(ErrorResponse {
             error: "At least one dimension (width or height) must be specified".to_string(),
         }));
     }
-    match resize_from_url(&query.url, query.width, query.height).await {
-        Ok(resized_bytes) => Ok(HttpResponse::Ok()
-            .content_type("image/png")
-            .body(resized_bytes)),
+    if let Err(error) = transform.validate() {
+        return Ok(HttpResponse::BadRequest().json(ErrorResponse { error }));
+    }
+    let output = OutputOptions::new(&req, &transform);
+    match resize_from_url(&query.url, &transform, &output).await {
+        Ok((resized_bytes, format)) => {
+            let mut response = HttpResponse::Ok();
+            response.content_type(format.content_type());
+            if output.is_negotiated() {
+                response.insert_header((VARY, "Accept"));
+            }
+            Ok(response.body(resized_bytes))
+        }
         Err(e) => {
+            if let Some(rejection) = fetch::rejection(&e) {
+                warn!("Image resize rejected: {}", rejection);
+                return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                    error: format!("URL not allowed: {}", rejection),
+                }));
+            }
+            if let Some(limit) = limit_exceeded(&e) {
+                warn!("Image resize rejected: {}", limit);
+                return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                    error: format!("Failed to resize image: {}", limit),
+                }));
+            }
             error!("Image resize error: {}", e);
             Ok(HttpResponse::InternalServerError().json(ErrorResponse {
                 error: format!("Failed to resize image: {}", e),
@@ -0,0 +1,234 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::{HttpRequest, HttpResponse, Result as ActixResult, web};
+use anyhow::Result;
+use bytes::Bytes;
+use lazy_static::lazy_static;
+use log::{debug, error, info, warn};
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ErrorResponse,
+    environment::{PROXY_CACHE_MAX_BYTES, PROXY_CACHE_SECONDS, SIGNING_SECRET},
+    fetch::{self, FetchError},
+    routes::preview_image::{self, OutputOptions, TransformOptions},
+    signature,
+};
+
+// Proxied responses are served from the CDN's origin, so make sure browsers
+// never treat them as anything but an image
+const CONTENT_SECURITY_POLICY: &str =
+    "default-src 'none'; img-src data:; style-src 'unsafe-inline'";
+
+lazy_static! {
+    static ref PROXY_CACHE: Mutex<ProxyCache> = Mutex::new(ProxyCache {
+        entries: LruCache::unbounded(),
+        size: 0,
+    });
+}
+
+#[derive(Clone)]
+struct ProxiedImage {
+    data: Bytes,
+    content_type: &'static str,
+    expires_at: Instant,
+}
+
+/// Least recently used images, evicted once their total size exceeds
+/// `PROXY_CACHE_MAX_BYTES`.
+struct ProxyCache {
+    entries: LruCache<String, ProxiedImage>,
+    size: usize,
+}
+
+impl ProxyCache {
+    fn get(&mut self, key: &str) -> Option<ProxiedImage> {
+        match self.entries.get(key) {
+            Some(image) if image.expires_at > Instant::now() => Some(image.clone()),
+            Some(_) => {
+                if let Some(image) = self.entries.pop(key) {
+                    self.size -= image.data.len();
+                }
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn put(&mut self, key: String, image: ProxiedImage) {
+        if image.data.len() > *PROXY_CACHE_MAX_BYTES {
+            return;
+        }
+        self.size += image.data.len();
+        if let Some(previous) = self.entries.put(key, image) {
+            self.size -= previous.data.len();
+        }
+        while self.size > *PROXY_CACHE_MAX_BYTES {
+            match self.entries.pop_lru() {
+                Some((_, evicted)) => self.size -= evicted.data.len(),
+                None => break,
+            }
+        }
+    }
+}
+
+/// Returns the path under which the proxy serves the URL with the given
+/// normalized transform parameters, or None if the proxy is disabled because
+/// no signing secret is configured.
+pub fn proxy_url(url: &str, params: &str) -> Option<String> {
+    let secret = SIGNING_SECRET.as_deref()?;
+    let path = format!(
+        "/proxy/{}/{}",
+        signature::sign_url(url, params, secret),
+        hex::encode(url)
+    );
+    if params.is_empty() {
+        Some(path)
+    } else {
+        Some(format!("{}?{}", path, params))
+    }
+}
+
+/// Fetches the image and checks that the body really is an image, regardless
+/// of the content type the origin claims.
+async fn fetch_image(
+    url: &str,
+    transform: &TransformOptions,
+    output: Option<&OutputOptions>,
+) -> Result<ProxiedImage> {
+    let image_bytes = preview_image::fetch_image(url).await?;
+    let format = image::guess_format(&image_bytes)
+        .map_err(|_| FetchError::ContentTypeNotAllowed("unknown".to_string()))?;
+    let (data, content_type) = if let Some(output) = output {
+        let (data, format) = preview_image::resize_bytes(image_bytes, transform, output).await?;
+        (data, format.content_type())
+    } else {
+        (image_bytes, format.to_mime_type())
+    };
+    if !content_type.starts_with("image/") {
+        return Err(FetchError::ContentTypeNotAllowed(content_type.to_string()).into());
+    }
+    Ok(ProxiedImage {
+        data,
+        content_type,
+        expires_at: Instant::now() + Duration::from_secs(*PROXY_CACHE_SECONDS),
+    })
+}
+
+pub async fn proxy_image(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    transform: web::Query<TransformOptions>,
+) -> ActixResult<HttpResponse> {
+    let (url_signature, encoded_url) = path.into_inner();
+    let Some(url) = hex::decode(&encoded_url)
+        .ok()
+        .and_then(|url| String::from_utf8(url).ok())
+    else {
+        return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+            error: "Invalid URL encoding".to_string(),
+        }));
+    };
+    if let Err(error) = transform.validate() {
+        return Ok(HttpResponse::BadRequest().json(ErrorResponse { error }));
+    }
+    // Transform parameters are signed too, so URLs can't be altered to
+    // generate arbitrary variants
+    if !SIGNING_SECRET.as_deref().is_some_and(|secret| {
+        signature::verify_url_signature(&url, &transform.normalized(), secret, &url_signature)
+    }) {
+        warn!("Invalid proxy signature for: {}", url);
+        return Ok(HttpResponse::Forbidden().json(ErrorResponse {
+            error: "Invalid signature".to_string(),
+        }));
+    }
+    info!("Proxying image: {}", url);
+
+    // Images are served as-is unless a transformation was requested
+    let output = (!transform.is_empty()).then(|| OutputOptions::new(&req, &transform));
+    let cache_key = match &output {
+        Some(output) => format!("{}|{:?}|{:?}", url, *transform, output),
+        None => url.clone(),
+    };
+    let cached = PROXY_CACHE.lock().unwrap().get(&cache_key);
+    let image = match cached {
+        Some(image) => {
+            debug!("Proxy cache hit: {}", url);
+            image
+        }
+        None => match fetch_image(&url, &transform, output.as_ref()).await {
+            Ok(image) => {
+                PROXY_CACHE.lock().unwrap().put(cache_key, image.clone());
+                image
+            }
+            Err(e) => {
+                if let Some(rejection) = fetch::rejection(&e) {
+                    warn!("Proxy request rejected: {}", rejection);
+                    return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                        error: format!("Failed to proxy image: {}", rejection),
+                    }));
+                }
+                if let Some(limit) = preview_image::limit_exceeded(&e) {
+                    warn!("Proxy request rejected: {}", limit);
+                    return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                        error: format!("Failed to proxy image: {}", limit),
+                    }));
+                }
+                error!("Proxy error for {}: {}", url, e);
+                return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                    error: format!("Failed to proxy image: {}", e),
+                }));
+            }
+        },
+    };
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type(image.content_type)
+        .insert_header((
+            "Cache-Control",
+            format!("public, max-age={}", *PROXY_CACHE_SECONDS),
+        ))
+        .insert_header(("Content-Security-Policy", CONTENT_SECURITY_POLICY))
+        .insert_header(("X-Content-Type-Options", "nosniff"));
+    if output.as_ref().is_some_and(OutputOptions::is_negotiated) {
+        response.insert_header(("Vary", "Accept"));
+    }
+    Ok(response.body(image.data))
+}
+
+#[derive(Deserialize)]
+pub struct ProxyUrlQuery {
+    url: String,
+}
+
+#[derive(Serialize)]
+pub struct ProxyUrlResponse {
+    url: String,
+}
+
+/// Signs a URL for the proxy, so clients can embed third-party images. Any
+/// transform parameters are signed along with it.
+pub async fn get_proxy_url(
+    query: web::Query<ProxyUrlQuery>,
+    transform: web::Query<TransformOptions>,
+) -> ActixResult<HttpResponse> {
+    if let Err(error) = transform.validate() {
+        return Ok(HttpResponse::BadRequest().json(ErrorResponse { error }));
+    }
+    if let Err(e) = url::Url::parse(&query.url)
+        .map_err(|_| FetchError::InvalidUrl)
+        .and_then(|url| fetch::validate_url(&url))
+    {
+        return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+            error: format!("Failed to sign URL: {}", e),
+        }));
+    }
+    match proxy_url(&query.url, &transform.normalized()) {
+        Some(url) => Ok(HttpResponse::Ok().json(ProxyUrlResponse { url })),
+        None => Ok(HttpResponse::NotFound().json(ErrorResponse {
+            error: "Image proxy is disabled".to_string(),
+        })),
+    }
+}
@@ -1,12 +1,18 @@
-use actix_web::{HttpResponse, Result as ActixResult, web};
+use actix_web::{HttpRequest, HttpResponse, Result as ActixResult, http::header::VARY, web};
+use anyhow::{Context, Result};
+use bytes::Bytes;
 use log::{error, info, warn};
 use serde::Deserialize;
 
 use crate::{
     ErrorResponse,
-    database::FileRepository,
-    environment::{S3_BUCKET, SIGNATURE_EXPIRY_SECONDS},
-    signature,
+    database::{
+        DerivativeRepository, FileDocument, FileRepository, VariantDocument, VariantRepository,
+    },
+    derivative,
+    environment::{S3_BUCKET, SIGNATURE_EXPIRY_SECONDS, SIGNING_SECRET},
+    routes::preview_image::{self, OutputOptions, TransformOptions},
+    signature, storage,
 };
 
 #[derive(Deserialize)]
@@ -15,12 +21,65 @@ pub struct FileServeQuery {
     timestamp: u64,
 }
 
+/// Returns the requested variant of an image file, generating and storing it
+/// on first use.
+async fn get_variant(
+    file_doc: &FileDocument,
+    transform: &TransformOptions,
+    output: &OutputOptions,
+) -> Result<(Bytes, String)> {
+    let params = format!("{}|{}", transform.normalized(), output.negotiation_key());
+    let key = derivative::variant_key(&file_doc.id, &params);
+    if let Some(variant) = VariantRepository::find_variant(&key).await? {
+        let response = S3_BUCKET
+            .get_object(&key)
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(storage::check_response)
+            .context("Failed to fetch variant from S3")?;
+        return Ok((response.bytes().clone(), variant.content_type));
+    }
+
+    info!("Generating variant {} of file {}", params, file_doc.id);
+    let original = S3_BUCKET
+        .get_object(file_doc.object_key())
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(storage::check_response)
+        .context("Failed to fetch file from S3")?;
+    let (data, format) =
+        preview_image::resize_bytes(original.bytes().clone(), transform, output).await?;
+    S3_BUCKET
+        .put_object_with_content_type(&key, &data, format.content_type())
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(storage::check_response)
+        .context("Failed to upload variant to S3")?;
+    let variant = VariantDocument::new(
+        key,
+        file_doc.id.clone(),
+        params,
+        format.content_type().to_string(),
+        data.len() as u64,
+    );
+    // The object is left for orphan reconciliation if this fails
+    if let Err(e) = VariantRepository::save_variant(variant).await {
+        warn!("Failed to save variant of file {}: {}", file_doc.id, e);
+    }
+    Ok((data, format.content_type().to_string()))
+}
+
 pub async fn serve_file(
+    req: HttpRequest,
     path: web::Path<String>,
     query: web::Query<FileServeQuery>,
+    transform: web::Query<TransformOptions>,
 ) -> ActixResult<HttpResponse> {
     let file_id = path.into_inner();
     info!("Serving file request for: {}", file_id);
+    if let Err(error) = transform.validate() {
+        return Ok(HttpResponse::BadRequest().json(ErrorResponse { error }));
+    }
     let file_doc = match FileRepository::get_file(&file_id).await {
         Ok(Some(doc)) => doc,
         Ok(None) => {
@@ -36,8 +95,10 @@ pub async fn serve_file(
             }));
         }
     };
+    // Transform parameters are signed too, so URLs can't be altered to
+    // generate arbitrary variants
     if !signature::verify_signature(
-        &file_id,
+        &signature::signed_resource(&file_id, &transform.normalized()),
         &file_doc.signing_key,
         &query.signature,
         query.timestamp,
@@ -48,7 +109,45 @@ pub async fn serve_file(
             error: "Invalid or expired signature".to_string(),
         }));
     }
-    match S3_BUCKET.get_object(&file_doc.id).await {
+    let content_disposition = format!(
+        "inline; filename=\"{}\"",
+        file_doc.name.clone().unwrap_or_else(|| file_doc.id.clone())
+    );
+
+    if !transform.is_empty() {
+        if !file_doc.content_type.starts_with("image/") {
+            return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Only images can be transformed".to_string(),
+            }));
+        }
+        let output = OutputOptions::new(&req, &transform);
+        return match get_variant(&file_doc, &transform, &output).await {
+            Ok((data, content_type)) => {
+                let mut response = HttpResponse::Ok();
+                response
+                    .content_type(content_type)
+                    .insert_header(("Content-Disposition", content_disposition));
+                if output.is_negotiated() {
+                    response.insert_header((VARY, "Accept"));
+                }
+                Ok(response.body(data))
+            }
+            Err(e) => {
+                if let Some(limit) = preview_image::limit_exceeded(&e) {
+                    warn!("Variant of file {} rejected: {}", file_id, limit);
+                    return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+                        error: format!("Failed to transform file: {}", limit),
+                    }));
+                }
+                error!("Failed to transform file {}: {}", file_id, e);
+                Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                    error: "Failed to transform file".to_string(),
+                }))
+            }
+        };
+    }
+
+    match S3_BUCKET.get_object(file_doc.object_key()).await {
         Ok(response) => {
             let bytes = response.bytes();
             info!(
@@ -58,13 +157,7 @@ pub async fn serve_file(
             );
             Ok(HttpResponse::Ok()
                 .content_type(file_doc.content_type.as_str())
-                .insert_header((
-                    "Content-Disposition",
-                    format!(
-                        "inline; filename=\"{}\"",
-                        file_doc.name.unwrap_or_else(|| file_doc.id.clone())
-                    ),
-                ))
+                .insert_header(("Content-Disposition", content_disposition))
                 .body(bytes.to_vec()))
         }
         Err(e) => {
@@ -75,3 +168,57 @@ pub async fn serve_file(
         }
     }
 }
+
+pub async fn serve_derivative(
+    path: web::Path<String>,
+    query: web::Query<FileServeQuery>,
+) -> ActixResult<HttpResponse> {
+    let hash = path.into_inner();
+    info!("Serving derivative request for: {}", hash);
+    // Without a secret no derivative URL can have been signed
+    if !SIGNING_SECRET.as_deref().is_some_and(|secret| {
+        signature::verify_signature(
+            &hash,
+            secret,
+            &query.signature,
+            query.timestamp,
+            *SIGNATURE_EXPIRY_SECONDS,
+        )
+    }) {
+        warn!("Invalid or expired signature for derivative: {}", hash);
+        return Ok(HttpResponse::Forbidden().json(ErrorResponse {
+            error: "Invalid or expired signature".to_string(),
+        }));
+    }
+    let derivative_doc = match DerivativeRepository::find_derivative(&hash).await {
+        Ok(Some(doc)) => doc,
+        Ok(None) => {
+            error!("Derivative not found: {}", hash);
+            return Ok(HttpResponse::NotFound().json(ErrorResponse {
+                error: "File not found".to_string(),
+            }));
+        }
+        Err(e) => {
+            error!("MongoDB error: {}", e);
+            return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Database error".to_string(),
+            }));
+        }
+    };
+    match S3_BUCKET
+        .get_object(derivative::derivative_key(&derivative_doc.hash))
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(storage::check_response)
+    {
+        Ok(response) => Ok(HttpResponse::Ok()
+            .content_type(derivative_doc.content_type.as_str())
+            .body(response.bytes().to_vec())),
+        Err(e) => {
+            error!("S3 fetch error for derivative {}: {}", hash, e);
+            Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                error: "Failed to fetch file".to_string(),
+            }))
+        }
+    }
+}
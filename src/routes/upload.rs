@@ -4,17 +4,35 @@ use bytes::Bytes;
 use futures_util::StreamExt;
 use log::{error, info, warn};
 use serde::Serialize;
+use sha2::{Digest, Sha256};
 use ulid::Ulid;
 
 use crate::{
-    ErrorResponse, clamav,
-    database::{FileDocument, FileRepository},
-    environment::S3_BUCKET,
+    ErrorResponse, audio, blob,
+    clamav::{self, ScanVerdict},
+    database::{
+        AudioMetadata, FileDocument, FileRepository, ImageMetadata, MediaMetadata,
+        SecurityEventDocument, SecurityEventRepository,
+    },
+    derivative,
+    environment::UPLOAD_STRIP_METADATA,
+    media, metadata,
+    routes::preview_image,
     signature,
 };
 
 pub const MAX_FILE_SIZE: u64 = 25 * 1024 * 1024; // 25MB in bytes
 
+// Stable error code returned to clients when an upload is rejected by ClamAV
+pub const MALWARE_DETECTED: &str = "malware_detected";
+
+#[derive(Serialize)]
+pub struct MalwareErrorResponse {
+    error: String,
+    code: &'static str,
+    signatures: Vec<String>,
+}
+
 #[derive(Serialize)]
 pub struct UploadResponse {
     id: String,
@@ -22,6 +40,11 @@ pub struct UploadResponse {
     content_type: String,
     signature: String,
     serve_url: String,
+    image: Option<ImageMetadata>,
+    media: Option<MediaMetadata>,
+    // Signed URL of the video's poster frame
+    poster_url: Option<String>,
+    audio: Option<AudioMetadata>,
 }
 
 pub async fn upload_file(req: HttpRequest, mut payload: Multipart) -> ActixResult<HttpResponse> {
@@ -39,6 +62,7 @@ pub async fn upload_file(req: HttpRequest, mut payload: Multipart) -> ActixResul
     let mut file_data: Option<Bytes> = None;
     let mut file_name: Option<String> = None;
     let mut content_type: Option<String> = None;
+    let mut hasher = Sha256::new();
 
     while let Some(item) = payload.next().await {
         let mut field =
@@ -58,16 +82,17 @@ pub async fn upload_file(req: HttpRequest, mut payload: Multipart) -> ActixResul
             while let Some(chunk) = field.next().await {
                 let data = chunk
                     .map_err(|e| actix_web::error::ErrorBadRequest(format!("Read error: {}", e)))?;
+                hasher.update(&data);
                 bytes.extend_from_slice(&data);
             }
             file_data = Some(Bytes::from(bytes));
             break;
         }
     }
-    let file_data =
+    let mut file_data =
         file_data.ok_or_else(|| actix_web::error::ErrorBadRequest("No file provided"))?;
     let content_type = content_type.unwrap_or_else(|| "application/octet-stream".to_string());
-    let file_size = file_data.len() as u64;
+    let mut file_size = file_data.len() as u64;
     if file_size > MAX_FILE_SIZE {
         warn!("File size {} exceeds limit of {}", file_size, MAX_FILE_SIZE);
         return Ok(HttpResponse::PayloadTooLarge().json(ErrorResponse {
@@ -78,13 +103,51 @@ pub async fn upload_file(req: HttpRequest, mut payload: Multipart) -> ActixResul
         }));
     }
 
+    let mut file_hash = hex::encode(hasher.finalize());
+
+    if *UPLOAD_STRIP_METADATA {
+        match metadata::strip(&file_data) {
+            Ok(Some(stripped)) => {
+                info!(
+                    "Stripped {} bytes of metadata from upload",
+                    file_size - stripped.len() as u64
+                );
+                file_hash = hex::encode(Sha256::digest(&stripped));
+                file_size = stripped.len() as u64;
+                file_data = Bytes::from(stripped);
+            }
+            Ok(None) => {}
+            // Stored as uploaded rather than rejecting files with unusual structure
+            Err(e) => warn!("Failed to strip metadata from upload: {}", e),
+        }
+    }
+
     info!("Scanning file with ClamAV");
-    match clamav::scan_bytes(&file_data).await {
-        Ok(true) => info!("File is clean"),
-        Ok(false) => {
-            error!("File is infected");
-            return Ok(HttpResponse::BadRequest().json(ErrorResponse {
+    match clamav::scan_bytes_cached(&file_data, &file_hash).await {
+        Ok(ScanVerdict::Clean) => info!("File is clean"),
+        Ok(ScanVerdict::Infected(signatures)) => {
+            error!(
+                "File {} uploaded by {} is infected: {}",
+                file_hash,
+                user_id,
+                signatures.join(", ")
+            );
+            let event = SecurityEventDocument::new(
+                MALWARE_DETECTED,
+                user_id,
+                file_hash,
+                signatures.clone(),
+                file_name,
+                content_type,
+                file_size,
+            );
+            if let Err(e) = SecurityEventRepository::insert_event(event).await {
+                error!("Failed to record security event: {}", e);
+            }
+            return Ok(HttpResponse::BadRequest().json(MalwareErrorResponse {
                 error: "File is infected with malware".to_string(),
+                code: MALWARE_DETECTED,
+                signatures,
             }));
         }
         Err(e) => {
@@ -95,21 +158,58 @@ pub async fn upload_file(req: HttpRequest, mut payload: Multipart) -> ActixResul
         }
     }
 
+    let image = if image::guess_format(&file_data).is_ok() {
+        match preview_image::analyze(file_data.clone()).await {
+            Ok(image) => Some(image),
+            Err(e) => {
+                warn!("Failed to read image metadata: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let media = if content_type.starts_with("video/") && media::is_enabled() {
+        match media::probe(&file_data).await {
+            Ok(media) => Some(media),
+            Err(e) => {
+                warn!("Failed to probe media: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let audio = if content_type.starts_with("audio/") {
+        match audio::analyze(file_data.clone(), &content_type).await {
+            Ok(audio) => Some(audio),
+            Err(e) => {
+                warn!("Failed to read audio metadata: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
     let file_id = Ulid::new().to_string();
-    info!("Uploading file to S3: {}", file_id);
-    match S3_BUCKET
-        .put_object_with_content_type(&file_id, &file_data, &content_type)
-        .await
-    {
+    info!("Storing file {} as blob {}", file_id, file_hash);
+    match blob::store(&file_hash, &file_data, &content_type).await {
         Ok(_) => {
             info!("File uploaded successfully: {}", file_id);
-            let file_doc = FileDocument::new(
+            let mut file_doc = FileDocument::new(
                 file_id.clone(),
                 file_name,
                 content_type.clone(),
                 file_size,
                 user_id.clone(),
+                file_hash.clone(),
             );
+            file_doc.image = image.clone();
+            file_doc.media = media.clone();
+            file_doc.audio = audio.clone();
             let (signature, timestamp) =
                 signature::generate_signature(&file_id, &file_doc.signing_key);
             let serve_url = format!(
@@ -118,8 +218,13 @@ pub async fn upload_file(req: HttpRequest, mut payload: Multipart) -> ActixResul
             );
             if let Err(e) = FileRepository::insert_file(file_doc).await {
                 error!("Failed to save file metadata to MongoDB: {}", e);
-                // TODO: delete the file from S3 here to avoid orphaned files?
-                warn!("File {} uploaded to S3 but not tracked in MongoDB", file_id);
+                if let Err(e) = blob::release(&file_hash).await {
+                    error!("Failed to release blob {}: {}", file_hash, e);
+                    warn!("Blob {} stored in S3 but not referenced", file_hash);
+                }
+                return Ok(HttpResponse::InternalServerError().json(ErrorResponse {
+                    error: "Failed to save file metadata".to_string(),
+                }));
             }
             Ok(HttpResponse::Ok().json(UploadResponse {
                 id: file_id,
@@ -127,6 +232,13 @@ pub async fn upload_file(req: HttpRequest, mut payload: Multipart) -> ActixResul
                 content_type,
                 signature,
                 serve_url,
+                image,
+                poster_url: media
+                    .as_ref()
+                    .and_then(|media| media.poster_hash.as_deref())
+                    .and_then(derivative::signed_url),
+                media,
+                audio,
             }))
         }
         Err(e) => {
@@ -0,0 +1,151 @@
+use anyhow::{Context, Result, anyhow};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use hmac::{Hmac, Mac};
+use http::{HeaderMap, HeaderValue};
+use lazy_static::lazy_static;
+use log::debug;
+use quick_xml::escape::escape;
+use s3::{request::ResponseData, signing};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+
+use crate::environment::S3_BUCKET;
+
+// S3 limits DeleteObjects requests to 1000 keys
+pub const MAX_DELETE_KEYS: usize = 1000;
+
+lazy_static! {
+    static ref S3_HTTP_CLIENT: reqwest::Client = reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(60))
+        .build()
+        .expect("Failed to create S3 HTTP client");
+}
+
+#[derive(Debug, Deserialize)]
+struct DeleteResult {
+    #[serde(rename = "Error", default)]
+    errors: Vec<DeleteError>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteError {
+    #[serde(rename = "Key")]
+    pub key: String,
+    #[serde(rename = "Code")]
+    pub code: String,
+    #[serde(rename = "Message", default)]
+    pub message: String,
+}
+
+fn long_datetime(datetime: &OffsetDateTime) -> String {
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        datetime.year(),
+        u8::from(datetime.month()),
+        datetime.day(),
+        datetime.hour(),
+        datetime.minute(),
+        datetime.second()
+    )
+}
+
+/// Turns a non-2xx S3 response into an error. rust-s3 is built without
+/// `fail-on-err`, so error responses are otherwise returned as `Ok`.
+pub fn check_response(response: ResponseData) -> Result<ResponseData> {
+    let status = response.status_code();
+    if !(200..300).contains(&status) {
+        return Err(anyhow!(
+            "S3 request failed with {}: {}",
+            status,
+            String::from_utf8_lossy(response.bytes())
+        ));
+    }
+    Ok(response)
+}
+
+/// Deletes up to `MAX_DELETE_KEYS` objects with a single S3 DeleteObjects
+/// request, which rust-s3 does not implement. Returns an entry for every key
+/// that could not be deleted; keys that do not exist count as deleted.
+pub async fn delete_objects(keys: &[String]) -> Result<Vec<DeleteError>> {
+    if keys.is_empty() {
+        return Ok(Vec::new());
+    }
+    if keys.len() > MAX_DELETE_KEYS {
+        return Err(anyhow!(
+            "Cannot delete more than {} objects per request",
+            MAX_DELETE_KEYS
+        ));
+    }
+
+    let mut body = String::from("<Delete><Quiet>true</Quiet>");
+    for key in keys {
+        body.push_str(&format!("<Object><Key>{}</Key></Object>", escape(key)));
+    }
+    body.push_str("</Delete>");
+
+    let url = url::Url::parse(&format!("{}/?delete", S3_BUCKET.url()))
+        .context("Failed to build DeleteObjects URL")?;
+    let credentials = S3_BUCKET.credentials().await?;
+    let region = S3_BUCKET.region();
+    let now = OffsetDateTime::now_utc();
+    let payload_hash = hex::encode(Sha256::digest(body.as_bytes()));
+
+    let mut headers = HeaderMap::new();
+    headers.insert("host", HeaderValue::from_str(&S3_BUCKET.host())?);
+    headers.insert(
+        "content-md5",
+        HeaderValue::from_str(&BASE64.encode(md5::compute(body.as_bytes()).0))?,
+    );
+    headers.insert(
+        "x-amz-content-sha256",
+        HeaderValue::from_str(&payload_hash)?,
+    );
+    headers.insert("x-amz-date", HeaderValue::from_str(&long_datetime(&now))?);
+    if let Some(token) = credentials.session_token.or(credentials.security_token) {
+        headers.insert("x-amz-security-token", HeaderValue::from_str(&token)?);
+    }
+
+    let canonical_request = signing::canonical_request("POST", &url, &headers, &payload_hash)?;
+    let string_to_sign = signing::string_to_sign(&now, &region, &canonical_request)?;
+    let signing_key = signing::signing_key(
+        &now,
+        credentials.secret_key.as_deref().unwrap_or_default(),
+        &region,
+        "s3",
+    )?;
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(&signing_key).expect("HMAC can take key of any size");
+    mac.update(string_to_sign.as_bytes());
+    let signature = hex::encode(mac.finalize().into_bytes());
+    let authorization = signing::authorization_header(
+        credentials.access_key.as_deref().unwrap_or_default(),
+        &now,
+        &region,
+        &signing::signed_header_string(&headers),
+        &signature,
+    )?;
+    headers.insert("authorization", HeaderValue::from_str(&authorization)?);
+    headers.insert("content-type", HeaderValue::from_static("application/xml"));
+
+    debug!("Deleting {} objects from S3", keys.len());
+    let response = S3_HTTP_CLIENT
+        .post(url)
+        .headers(headers)
+        .body(body)
+        .send()
+        .await
+        .context("Failed to send DeleteObjects request")?;
+    let status = response.status();
+    let text = response
+        .text()
+        .await
+        .context("Failed to read DeleteObjects response")?;
+    if !status.is_success() {
+        return Err(anyhow!("DeleteObjects failed with {}: {}", status, text));
+    }
+
+    let result: DeleteResult =
+        quick_xml::de::from_str(&text).context("Failed to parse DeleteObjects response")?;
+    Ok(result.errors)
+}
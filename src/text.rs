@@ -0,0 +1,84 @@
+use encoding_rs::{Encoding, UTF_8};
+use scraper::Html;
+
+// The HTML spec only looks for <meta charset> in the first 1024 bytes
+const META_PRESCAN_BYTES: usize = 1024;
+
+/// Decodes an HTML document, taking the encoding from the byte order mark,
+/// the `charset` of the Content-Type header or a `<meta charset>` tag, in
+/// that order, and falling back to UTF-8.
+pub fn decode_html(body: &[u8], header_charset: Option<&str>) -> String {
+    if let Some((encoding, bom_length)) = Encoding::for_bom(body) {
+        return encoding
+            .decode_without_bom_handling(&body[bom_length..])
+            .0
+            .into_owned();
+    }
+    let encoding = header_charset
+        .and_then(|label| Encoding::for_label(label.as_bytes()))
+        .or_else(|| prescan_meta_charset(body))
+        .unwrap_or(UTF_8);
+    encoding.decode_without_bom_handling(body).0.into_owned()
+}
+
+fn prescan_meta_charset(body: &[u8]) -> Option<&'static Encoding> {
+    let prefix = &body[..body.len().min(META_PRESCAN_BYTES)];
+    let prefix = String::from_utf8_lossy(prefix).to_ascii_lowercase();
+    for (index, _) in prefix.match_indices("<meta") {
+        let tag = &prefix[index..];
+        let tag = &tag[..tag.find('>').unwrap_or(tag.len())];
+        // Covers both <meta charset="..."> and <meta http-equiv content="...; charset=...">
+        let Some(start) = tag.find("charset=") else {
+            continue;
+        };
+        let label: String = tag[start + "charset=".len()..]
+            .trim_start_matches(['"', '\'', ' '])
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | ':' | '.'))
+            .collect();
+        if let Some(encoding) = Encoding::for_label(label.as_bytes()) {
+            // A document that could be read this far is not actually UTF-16
+            if encoding.name().starts_with("UTF-16") {
+                return Some(UTF_8);
+            }
+            return Some(encoding);
+        }
+    }
+    None
+}
+
+/// Collapses runs of whitespace into single spaces and trims the ends.
+pub fn normalize_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Converts raw markup, such as a JSON-LD value, into plain text by
+/// stripping tags and decoding entities once. Text that was already decoded
+/// by the HTML parser must not go through this again.
+pub fn markup_to_text(markup: &str) -> String {
+    if !markup.contains(['<', '&']) {
+        return markup.to_string();
+    }
+    Html::parse_fragment(markup).root_element().text().collect()
+}
+
+/// Normalizes the whitespace of plain text and truncates it to `max_chars`.
+/// Returns None if nothing is left.
+pub fn clean_text(text: &str, max_chars: usize) -> Option<String> {
+    let text = normalize_whitespace(text);
+    if text.is_empty() {
+        return None;
+    }
+    Some(truncate(&text, max_chars))
+}
+
+/// Shortens text to at most `max_chars` characters, ending in an ellipsis if
+/// anything was cut.
+pub fn truncate(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars.saturating_sub(1)) {
+        Some((index, _)) if text[index..].chars().nth(1).is_some() => {
+            format!("{}…", text[..index].trim_end())
+        }
+        _ => text.to_string(),
+    }
+}